@@ -0,0 +1,4 @@
+-- img_url now holds a storage key instead of a path relative to the working directory
+UPDATE posts
+SET img_url = regexp_replace(img_url, '^\./files/', '')
+WHERE img_url LIKE './files/%';
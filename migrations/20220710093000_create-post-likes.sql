@@ -0,0 +1,7 @@
+-- One row per (post, user), so a user can like a post only once
+create table post_likes (
+    post_id uuid not null references posts (id) on delete cascade,
+    username varchar not null,
+    created_at timestamp not null default current_timestamp,
+    PRIMARY KEY (post_id, username)
+);
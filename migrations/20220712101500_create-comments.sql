@@ -0,0 +1,12 @@
+-- Comments are removed together with their post
+create table comments (
+    id uuid not null,
+    PRIMARY KEY (id),
+    post_id uuid not null references posts (id) on delete cascade,
+    username varchar not null,
+    body varchar not null,
+    created_at timestamp not null default current_timestamp,
+    updated_at timestamp
+);
+
+create index comments_post_id_created_at_idx on comments (post_id, created_at);
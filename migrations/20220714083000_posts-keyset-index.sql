@@ -0,0 +1,2 @@
+-- Backs keyset pagination over (created_at, id), newest first
+create index posts_username_created_at_id_idx on posts (username, created_at desc, id desc);
@@ -0,0 +1,2 @@
+-- Resized copies of the image, {"<size>": {"webp": "<key>", "jpeg": "<key>"}}
+alter table posts add column variants jsonb not null default '{}';
@@ -0,0 +1,16 @@
+-- Carousel posts keep their images here, in display order
+create table post_media (
+    id uuid not null,
+    PRIMARY KEY (id),
+    post_id uuid not null references posts (id) on delete cascade,
+    position smallint not null,
+    img_url varchar not null unique,
+    alt_text varchar,
+    variants jsonb not null default '{}',
+    unique (post_id, position)
+);
+
+insert into post_media (id, post_id, position, img_url, variants)
+select uuid_generate_v4(), id, 0, img_url, variants from posts;
+
+alter table posts drop column img_url, drop column variants;
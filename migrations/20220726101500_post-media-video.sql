@@ -0,0 +1,7 @@
+-- Videos share the table with images, their variants are renditions of the poster
+alter table post_media
+    add column kind varchar not null default 'image',
+    add column width integer,
+    add column height integer,
+    add column duration_ms integer,
+    add column codec varchar;
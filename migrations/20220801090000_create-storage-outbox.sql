@@ -0,0 +1,17 @@
+-- Storage work owed once a transaction commits: staged uploads to move into place, deleted media to remove
+create table storage_outbox (
+    id bigserial,
+    PRIMARY KEY (id),
+    action varchar not null,
+    key varchar not null,
+    attempts integer not null default 0,
+    last_error varchar,
+    created_at timestamp not null default current_timestamp,
+    next_attempt_at timestamp not null default current_timestamp
+);
+
+create index storage_outbox_next_attempt_at_idx on storage_outbox (next_attempt_at);
+create index storage_outbox_key_idx on storage_outbox (key);
+
+-- The reaper matches objects to media by the stem shared by an original and its variants
+create index post_media_stem_idx on post_media (split_part(img_url, '.', 1));
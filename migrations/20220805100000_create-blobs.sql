@@ -0,0 +1,40 @@
+-- Uploads are stored once per distinct content and shared by every post carrying them.
+-- Media from before hashes were taken become blobs of their own, without one
+create table blobs (
+    id uuid not null,
+    PRIMARY KEY (id),
+    sha256 char(64) unique,
+    img_url varchar not null unique,
+    variants jsonb not null default '{}',
+    kind varchar not null default 'image',
+    width integer,
+    height integer,
+    duration_ms integer,
+    codec varchar,
+    ref_count integer not null default 0 check (ref_count >= 0),
+    created_at timestamp not null default current_timestamp
+);
+
+insert into blobs (id, img_url, variants, kind, width, height, duration_ms, codec, ref_count)
+select id, img_url, variants, kind, width, height, duration_ms, codec, 1 from post_media;
+
+alter table post_media
+    add column blob_id uuid references blobs (id),
+    add column repost_of uuid references posts (id) on delete set null;
+
+update post_media set blob_id = id;
+
+alter table post_media
+    alter column blob_id set not null,
+    drop column img_url,
+    drop column variants,
+    drop column kind,
+    drop column width,
+    drop column height,
+    drop column duration_ms,
+    drop column codec;
+
+create index post_media_blob_id_idx on post_media (blob_id);
+
+-- The reaper matches objects to blobs by the stem shared by an original and its variants
+create index blobs_stem_idx on blobs (split_part(img_url, '.', 1));
@@ -0,0 +1,14 @@
+-- Resumable uploads in progress, their bytes are kept on disk under the session id
+create table upload_sessions (
+    id uuid not null,
+    PRIMARY KEY (id),
+    username varchar not null,
+    file_name varchar not null,
+    content_type varchar not null,
+    upload_length bigint not null check (upload_length > 0),
+    upload_offset bigint not null default 0 check (upload_offset <= upload_length),
+    created_at timestamp not null default current_timestamp,
+    expires_at timestamp not null
+);
+
+create index upload_sessions_expires_at_idx on upload_sessions (expires_at);
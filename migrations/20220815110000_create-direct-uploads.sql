@@ -0,0 +1,13 @@
+-- Uploads announced before the client sends them straight to storage, under `incoming/<id>`
+create table direct_uploads (
+    id uuid not null,
+    PRIMARY KEY (id),
+    username varchar not null,
+    content_type varchar not null,
+    size bigint not null check (size > 0),
+    sha256 char(64),
+    created_at timestamp not null default current_timestamp,
+    expires_at timestamp not null
+);
+
+create index direct_uploads_expires_at_idx on direct_uploads (expires_at);
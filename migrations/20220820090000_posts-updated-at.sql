@@ -0,0 +1,8 @@
+-- Last change to what a post reads like, caption edits and likes, validates cached copies
+alter table posts add column updated_at timestamp;
+
+update posts set updated_at = created_at;
+
+alter table posts
+    alter column updated_at set not null,
+    alter column updated_at set default current_timestamp;
@@ -0,0 +1,4 @@
+-- Shown while the first image of a post loads, null until computed for older posts
+alter table posts
+    add column blurhash varchar,
+    add column dominant_color char(7);
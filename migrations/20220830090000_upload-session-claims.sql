@@ -0,0 +1,4 @@
+-- The request writing to or posting a session, so instances behind a load balancer take turns
+alter table upload_sessions
+    add column claimed_by uuid,
+    add column claimed_until timestamp;
@@ -0,0 +1,535 @@
+mod jwt;
+
+use reqwest::{Client, StatusCode};
+
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use actix_web::{dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform}, Error, FromRequest, HttpMessage, HttpRequest, web};
+use actix_web::body::EitherBody;
+use actix_web::http::header::{Header, TryIntoHeaderValue};
+use actix_web_httpauth::headers::authorization::{Authorization, Bearer};
+use actix_web_httpauth::headers::www_authenticate::WwwAuthenticate;
+use actix_web_httpauth::headers::www_authenticate::bearer::{Bearer as BearerChallenge, Error as BearerError};
+
+use futures_util::future::LocalBoxFuture;
+use moka::Expiry;
+use moka::sync::Cache;
+use sha2::{Digest, Sha256};
+
+use crate::configuration::{AuthClientSettings, AuthMode, CredentialSource, FailurePolicy};
+use crate::error::ApiError;
+
+pub use jwt::JwtVerifier;
+
+
+/// Header naming the user for bearer requests, the counterpart of the `username` cookie.
+pub const USERNAME_HEADER: &str = "X-Username";
+
+/// Cached verifications are keyed by username and the SHA-256 of the token,
+/// so raw tokens never sit in memory longer than the request.
+type VerificationKey = (String, [u8; 32]);
+
+pub struct AuthClient {
+    pub http_client: Client,
+    pub base_url: String,
+    failure_policy: FailurePolicy,
+    verifications: Cache<VerificationKey, bool>,
+    /// Credentials verified within `open_grace_seconds`, what `FailurePolicy::Open` lets in.
+    verified_before: Cache<VerificationKey, ()>,
+    /// Set in `jwt` mode, tokens are then verified locally.
+    jwt: Option<JwtVerifier>,
+    /// Where to look for credentials, first match wins.
+    credential_sources: Vec<CredentialSource>,
+    realm: String,
+}
+
+/// What a client presented, before verification.
+#[derive(Debug)]
+pub struct Credentials {
+    /// Optional in `jwt` mode, where the token names the user.
+    pub username: Option<String>,
+    pub access_token: String
+}
+
+impl AuthClient {
+    pub fn new(settings: &AuthClientSettings) -> eyre::Result<Self> {
+        let http_client = Client::builder()
+            .timeout(settings.timeout())
+            .build()
+            .expect("Failed to build the auth service http client");
+
+        let verifications = Cache::builder()
+            .max_capacity(settings.cache_capacity)
+            .expire_after(VerificationExpiry {
+                positive_ttl: Duration::from_secs(settings.positive_ttl_seconds),
+                negative_ttl: Duration::from_secs(settings.negative_ttl_seconds)
+            })
+            .build();
+        let verified_before = Cache::builder()
+            .max_capacity(settings.cache_capacity)
+            .time_to_live(Duration::from_secs(settings.open_grace_seconds))
+            .build();
+
+        let jwt = match settings.mode {
+            AuthMode::Remote => None,
+            AuthMode::Jwt => {
+                let jwt_settings = settings.jwt.as_ref()
+                    .ok_or_else(|| eyre::eyre!("auth_client.jwt is required in jwt mode"))?;
+                Some(JwtVerifier::new(jwt_settings)?)
+            }
+        };
+
+        Ok(AuthClient {
+            http_client,
+            base_url: settings.base_url.clone(),
+            failure_policy: settings.failure_policy,
+            verifications,
+            verified_before,
+            jwt,
+            credential_sources: settings.credential_sources.clone(),
+            realm: settings.realm.clone()
+        })
+    }
+
+    /// Pull credentials out of the request following the configured precedence.
+    pub fn credentials(&self, req: &ServiceRequest) -> Option<Credentials> {
+        self.credential_sources.iter().find_map(|source| match source {
+            CredentialSource::Bearer => {
+                let authorization = Authorization::<Bearer>::parse(req).ok()?;
+                let username = req.headers().get(USERNAME_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                    .map(String::from);
+                Some(Credentials {
+                    username,
+                    access_token: authorization.as_ref().token().to_string()
+                })
+            },
+            CredentialSource::Cookie => {
+                let access_token = req.cookie("access_token")?;
+                Some(Credentials {
+                    username: req.cookie("username").map(|c| c.value().to_string()),
+                    access_token: access_token.value().to_string()
+                })
+            }
+        })
+    }
+
+    /// Turn the request away with the usual `ApiError` body and a `WWW-Authenticate: Bearer`
+    /// challenge.
+    ///
+    /// The status follows the error code (401 for `invalid_token`, 400 for `invalid_request`).
+    /// No error code when the request had no credentials at all, as RFC 6750 asks.
+    pub fn challenge(&self, req: ServiceRequest, error: Option<BearerError>) -> ServiceResponse {
+        let mut challenge = BearerChallenge::build().realm(self.realm.clone());
+        if let Some(error) = error {
+            challenge = challenge.error(error);
+        }
+        let api_error = match error {
+            None => ApiError::Unauthorized("Credentials are required".into()),
+            Some(BearerError::InvalidToken) => ApiError::Unauthorized("Invalid access token".into()),
+            Some(BearerError::InvalidRequest) => ApiError::BadRequest(
+                format!("The {} header or username cookie is required", USERNAME_HEADER)
+            ),
+            Some(BearerError::InsufficientScope) => ApiError::Forbidden("Insufficient scope".into())
+        };
+
+        let mut res = req.error_response(api_error);
+        let header = WwwAuthenticate(challenge.finish());
+        if let Ok(value) = header.try_into_value() {
+            res.headers_mut().insert(WwwAuthenticate::<BearerChallenge>::name(), value);
+        }
+        res
+    }
+
+    /// Whether credentials have to name the user. Only JWTs carry the username themselves.
+    pub fn requires_username(&self) -> bool {
+        self.jwt.is_none()
+    }
+
+    /// Returns the verified username, or `None` when the credentials are rejected.
+    pub async fn authorize(&self, credentials: &Credentials) -> Result<Option<String>, Error> {
+        if let Some(jwt) = &self.jwt {
+            return Ok(Self::authorize_jwt(jwt, credentials))
+        }
+
+        // Turned away with `invalid_request` by the middleware, see `requires_username`
+        let username = match &credentials.username {
+            Some(username) => username,
+            None => return Ok(None)
+        };
+        let verified = self.authorize_remote(username, &credentials.access_token).await?;
+        Ok(verified.then(|| username.clone()))
+    }
+
+    /// The username comes from the token; one sent alongside it has to agree.
+    fn authorize_jwt(jwt: &JwtVerifier, credentials: &Credentials) -> Option<String> {
+        let username = match jwt.verify(&credentials.access_token) {
+            Ok(username) => username,
+            Err(e) => {
+                tracing::info!("Rejected access token: {:?}", e);
+                return None
+            }
+        };
+        match &credentials.username {
+            Some(claimed) if *claimed != username => None,
+            _ => Some(username)
+        }
+    }
+
+    async fn authorize_remote(&self, username: &str, access_token: &str) -> Result<bool, Error> {
+
+        let key: VerificationKey = (
+            username.to_string(),
+            Sha256::digest(access_token.as_bytes()).into()
+        );
+        if let Some(verified) = self.verifications.get(&key) {
+            return Ok(verified)
+        }
+
+        let body = Verify {
+            username,
+            access_token
+        };
+
+        match self.verify(&body).await {
+            Ok(verified) => {
+                if verified {
+                    self.verified_before.insert(key.clone(), ());
+                } else {
+                    self.verified_before.invalidate(&key);
+                }
+                self.verifications.insert(key, verified);
+                Ok(verified)
+            },
+            Err(e) => {
+                tracing::error!("Unable to access auth service: {:?}", e);
+                match self.failure_policy {
+                    // Only credentials the service accepted before, never unknown ones
+                    FailurePolicy::Open if self.verified_before.contains_key(&key) => {
+                        tracing::warn!("Failing open, letting {} through on an earlier verification", body.username);
+                        Ok(true)
+                    },
+                    _ => Err(
+                        ApiError::ServiceUnavailable("Authentication service not available".into()).into()
+                    )
+                }
+            }
+        }
+    }
+
+    /// Ask the auth service; transport errors, timeouts and 5xx answers are errors, not denials.
+    async fn verify(&self, body: &Verify<'_>) -> Result<bool, reqwest::Error> {
+        let res = self.http_client
+            .post(&self.base_url)
+            .json(body)
+            .send()
+            .await?;
+        if res.status().is_server_error() {
+            return res.error_for_status().map(|_| false)
+        }
+        Ok(res.status() == StatusCode::OK)
+    }
+}
+
+/// Denials are kept for a shorter time than successful verifications.
+struct VerificationExpiry {
+    positive_ttl: Duration,
+    negative_ttl: Duration
+}
+
+impl Expiry<VerificationKey, bool> for VerificationExpiry {
+    fn expire_after_create(&self, _key: &VerificationKey, verified: &bool, _created_at: Instant) -> Option<Duration> {
+        if *verified {
+            Some(self.positive_ttl)
+        } else {
+            Some(self.negative_ttl)
+        }
+    }
+}
+
+
+#[derive(serde::Serialize)]
+pub struct Verify<'a> {
+    username: &'a str,
+    access_token: &'a str
+}
+
+/// The caller as verified by `AuthorMiddleware`.
+///
+/// The middleware stores it in the request extensions; handlers behind `Author`
+/// take it as an extractor instead of trusting usernames sent by the client.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub username: String
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<AuthenticatedUser>()
+                .cloned()
+                .ok_or_else(|| ApiError::Unauthorized("User is not authenticated".into()).into())
+        )
+    }
+}
+
+// There are two steps in middleware processing.
+// 1. Middleware initialization, middleware factory gets called with
+//    next service in chain as parameter.
+// 2. Middle-ware's call method gets called with normal request.
+pub struct Author;
+
+// Middleware factory is `Transform` trait
+// `S` - type of the next service
+// `B` - type of response's body
+impl<S, B> Transform<S, ServiceRequest> for Author
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AuthorMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthorMiddleware { service: Rc::new(service) }))
+    }
+}
+
+pub struct AuthorMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthorMiddleware<S>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let client = req.app_data::<web::Data<AuthClient>>()
+            .expect("AuthClient not found in server data domain").clone();
+
+        let credentials = client.credentials(&req);
+
+        let service = self.service.clone();
+
+        Box::pin(async move {
+
+            let credentials = match credentials {
+                Some(credentials) => credentials,
+                None => return Ok(client.challenge(req, None).map_into_right_body())
+            };
+            if credentials.username.is_none() && client.requires_username() {
+                return Ok(client.challenge(req, Some(BearerError::InvalidRequest)).map_into_right_body())
+            }
+
+            // Only hand the request to the next service once the caller is verified
+            let auth_fut = client.authorize(&credentials);
+            let username = match auth_fut.await? {
+                Some(username) => username,
+                None => return Ok(client.challenge(req, Some(BearerError::InvalidToken)).map_into_right_body())
+            };
+
+            req.extensions_mut().insert(AuthenticatedUser { username });
+
+            let res = service.call(req).await?;
+
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use actix_web::{App, HttpResponse};
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::cookie::Cookie;
+    use actix_web::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+    use actix_web::http::StatusCode as HttpStatus;
+    use jsonwebtoken::{encode, EncodingKey};
+    use secrecy::Secret;
+    use serde_json::json;
+    use super::*;
+    use crate::configuration::{JwtAlgorithm, JwtSettings};
+
+    const SECRET: &str = "auth-test-secret";
+
+    fn settings(mode: AuthMode, credential_sources: Vec<CredentialSource>) -> AuthClientSettings {
+        AuthClientSettings {
+            mode,
+            // Never reached, remote verification is not what these tests are about
+            base_url: "http://127.0.0.1:9/auth".into(),
+            timeout_milliseconds: 100,
+            cache_capacity: 10,
+            positive_ttl_seconds: 60,
+            negative_ttl_seconds: 10,
+            failure_policy: FailurePolicy::Closed,
+            open_grace_seconds: 0,
+            jwt: Some(JwtSettings {
+                algorithm: JwtAlgorithm::HS256,
+                secret: Some(Secret::new(SECRET.into())),
+                jwks_path: None,
+                audience: None,
+                username_claim: "sub".into(),
+                leeway_seconds: 0
+            }),
+            credential_sources,
+            realm: "poster-test".into()
+        }
+    }
+
+    fn client(credential_sources: Vec<CredentialSource>) -> AuthClient {
+        AuthClient::new(&settings(AuthMode::Jwt, credential_sources)).unwrap()
+    }
+
+    fn token(username: &str) -> String {
+        let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 60;
+        encode(
+            &jsonwebtoken::Header::default(),
+            &json!({ "sub": username, "exp": exp }),
+            &EncodingKey::from_secret(SECRET.as_bytes())
+        ).unwrap()
+    }
+
+    /// Bearer credentials for `bob-token` and cookies for `carol-token`.
+    fn both() -> TestRequest {
+        TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer bob-token"))
+            .insert_header((USERNAME_HEADER, "bob"))
+            .cookie(Cookie::new("access_token", "carol-token"))
+            .cookie(Cookie::new("username", "carol"))
+    }
+
+    #[test]
+    fn the_first_configured_source_wins() {
+        use CredentialSource::{Bearer, Cookie as FromCookie};
+
+        let bearer_first = client(vec![Bearer, FromCookie]).credentials(&both().to_srv_request()).unwrap();
+        assert_eq!(bearer_first.access_token, "bob-token");
+        assert_eq!(bearer_first.username.as_deref(), Some("bob"));
+
+        let cookie_first = client(vec![FromCookie, Bearer]).credentials(&both().to_srv_request()).unwrap();
+        assert_eq!(cookie_first.access_token, "carol-token");
+        assert_eq!(cookie_first.username.as_deref(), Some("carol"));
+    }
+
+    #[test]
+    fn a_missing_source_falls_back_to_the_next() {
+        use CredentialSource::{Bearer, Cookie as FromCookie};
+
+        let cookies_only = TestRequest::default()
+            .cookie(Cookie::new("access_token", "carol-token"))
+            .to_srv_request();
+        let from_cookie = client(vec![Bearer, FromCookie]).credentials(&cookies_only).unwrap();
+        assert_eq!(from_cookie.access_token, "carol-token");
+        assert_eq!(from_cookie.username, None);
+
+        let bearer_only = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer bob-token"))
+            .to_srv_request();
+        let from_bearer = client(vec![FromCookie, Bearer]).credentials(&bearer_only).unwrap();
+        assert_eq!(from_bearer.access_token, "bob-token");
+
+        // Only the configured sources are looked at
+        assert!(client(vec![FromCookie]).credentials(&bearer_only).is_none());
+        assert!(client(vec![Bearer]).credentials(&TestRequest::default().to_srv_request()).is_none());
+    }
+
+    #[test]
+    fn malformed_authorization_headers_are_not_credentials() {
+        let client = client(vec![CredentialSource::Bearer, CredentialSource::Cookie]);
+        for header in ["Bearer", "Bearer ", "Basic Ym9iOnNlY3JldA==", "bob-token"] {
+            let req = TestRequest::default().insert_header((AUTHORIZATION, header)).to_srv_request();
+            assert!(client.credentials(&req).is_none(), "{} was taken", header);
+
+            let with_cookie = TestRequest::default()
+                .insert_header((AUTHORIZATION, header))
+                .cookie(Cookie::new("access_token", "carol-token"))
+                .to_srv_request();
+            assert_eq!(client.credentials(&with_cookie).unwrap().access_token, "carol-token");
+        }
+    }
+
+    async fn whoami(user: AuthenticatedUser) -> HttpResponse {
+        HttpResponse::Ok().body(user.username)
+    }
+
+    /// Status and `WWW-Authenticate` of a request to a route behind `Author`.
+    async fn call(client: AuthClient, req: TestRequest) -> (HttpStatus, Option<String>, String) {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(client))
+                .wrap(Author)
+                .route("/", web::get().to(whoami))
+        ).await;
+        let res = call_service(&app, req.to_request()).await;
+        let status = res.status();
+        let challenge = res.headers().get(WWW_AUTHENTICATE).map(|v| v.to_str().unwrap().to_string());
+        let body = String::from_utf8(read_body(res).await.to_vec()).unwrap();
+        (status, challenge, body)
+    }
+
+    #[actix_web::test]
+    async fn requests_without_credentials_get_a_bare_challenge() {
+        let (status, challenge, _) = call(client(vec![CredentialSource::Bearer]), TestRequest::get()).await;
+        assert_eq!(status, HttpStatus::UNAUTHORIZED);
+        assert_eq!(challenge.as_deref(), Some("Bearer realm=\"poster-test\""));
+    }
+
+    #[actix_web::test]
+    async fn rejected_tokens_get_an_invalid_token_challenge() {
+        let req = TestRequest::get().insert_header((AUTHORIZATION, "Bearer not-a-jwt"));
+        let (status, challenge, _) = call(client(vec![CredentialSource::Bearer]), req).await;
+
+        assert_eq!(status, HttpStatus::UNAUTHORIZED);
+        let challenge = challenge.unwrap();
+        assert!(challenge.starts_with("Bearer "), "{}", challenge);
+        assert!(challenge.contains("realm=\"poster-test\""), "{}", challenge);
+        assert!(challenge.contains("error=\"invalid_token\""), "{}", challenge);
+    }
+
+    #[actix_web::test]
+    async fn mismatched_usernames_get_an_invalid_token_challenge() {
+        let req = TestRequest::get()
+            .insert_header((AUTHORIZATION, format!("Bearer {}", token("alice"))))
+            .insert_header((USERNAME_HEADER, "bob"));
+        let (status, challenge, _) = call(client(vec![CredentialSource::Bearer]), req).await;
+        assert_eq!(status, HttpStatus::UNAUTHORIZED);
+        assert!(challenge.unwrap().contains("error=\"invalid_token\""));
+    }
+
+    #[actix_web::test]
+    async fn remote_mode_asks_for_the_username() {
+        let client = AuthClient::new(&settings(AuthMode::Remote, vec![CredentialSource::Bearer])).unwrap();
+        let req = TestRequest::get().insert_header((AUTHORIZATION, "Bearer good-alice"));
+        let (status, challenge, _) = call(client, req).await;
+        assert_eq!(status, HttpStatus::BAD_REQUEST);
+        assert!(challenge.unwrap().contains("error=\"invalid_request\""));
+    }
+
+    #[actix_web::test]
+    async fn verified_users_reach_the_handler() {
+        let req = TestRequest::get().insert_header((AUTHORIZATION, format!("Bearer {}", token("alice"))));
+        let (status, challenge, body) = call(client(vec![CredentialSource::Bearer]), req).await;
+        assert_eq!(status, HttpStatus::OK);
+        assert_eq!(challenge, None);
+        assert_eq!(body, "alice");
+    }
+}
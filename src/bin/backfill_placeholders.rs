@@ -0,0 +1,33 @@
+use sqlx::postgres::PgPoolOptions;
+use poster::placeholders;
+use poster::storage;
+
+use poster::configuration::get_configuration;
+use poster::telemetry::{get_subscriber, init_subscriber};
+
+
+/// Computes the placeholders of posts created before uploads got them.
+#[actix_web::main]
+async fn main() -> eyre::Result<()> {
+
+    let subscriber = get_subscriber(
+        "poster-backfill-placeholders".into(),
+        "info".into(),
+        std::io::stdout
+    );
+    init_subscriber(subscriber);
+
+    let configuration = get_configuration()
+        .expect("Failed to read configuration");
+
+    let connection_pool = PgPoolOptions::new()
+        .connect_timeout(std::time::Duration::from_secs(2))
+        .connect_lazy_with(configuration.database.with_db());
+
+    let storage = storage::build(&configuration.storage, &configuration.file_urls)
+        .expect("Failed to initialize storage backend");
+
+    let filled = placeholders::backfill(&connection_pool, storage.as_ref()).await?;
+    tracing::info!("Filled in the placeholders of {} post(s)", filled);
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use std::time::Duration;
 use secrecy::{ExposeSecret, Secret};
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::ConnectOptions;
@@ -8,7 +9,14 @@ use sqlx::postgres::{PgConnectOptions, PgSslMode};
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
-    pub auth_client: AuthClientSettings
+    pub auth_client: AuthClientSettings,
+    pub storage: StorageSettings,
+    pub media: MediaSettings,
+    pub reaper: ReaperSettings,
+    pub resumable: ResumableSettings,
+    pub direct_uploads: DirectUploadSettings,
+    pub file_urls: FileUrlSettings,
+    pub images: ImageSettings
 }
 
 #[derive(serde::Deserialize)]
@@ -20,7 +28,267 @@ pub struct ApplicationSettings {
 
 #[derive(serde::Deserialize)]
 pub struct AuthClientSettings {
-    pub base_url: String
+    #[serde(default)]
+    pub mode: AuthMode,
+    pub base_url: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub timeout_milliseconds: u64,
+    /// Upper bound on cached (username, token) verifications.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub cache_capacity: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub positive_ttl_seconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub negative_ttl_seconds: u64,
+    pub failure_policy: FailurePolicy,
+    /// How long a positive verification still lets its (username, token) pair in under the
+    /// `open` policy while the auth service is down.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub open_grace_seconds: u64,
+    /// Required when `mode` is `jwt`.
+    pub jwt: Option<JwtSettings>,
+    /// Where `AuthorMiddleware` looks for credentials, in order of precedence.
+    #[serde(default = "default_credential_sources")]
+    pub credential_sources: Vec<CredentialSource>,
+    /// Realm advertised in `WWW-Authenticate` challenges.
+    #[serde(default = "default_realm")]
+    pub realm: String
+}
+
+fn default_credential_sources() -> Vec<CredentialSource> {
+    vec![CredentialSource::Bearer, CredentialSource::Cookie]
+}
+
+fn default_realm() -> String {
+    "poster".into()
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CredentialSource {
+    /// `Authorization: Bearer <token>`, the username, if needed, in `X-Username`.
+    Bearer,
+    /// `access_token` and `username` cookies.
+    Cookie
+}
+
+impl AuthClientSettings {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_milliseconds)
+    }
+}
+
+/// How `AuthClient` verifies access tokens.
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    /// Ask the auth service on every (uncached) request.
+    #[default]
+    Remote,
+    /// Treat access tokens as signed JWTs and verify them locally.
+    Jwt
+}
+
+#[derive(serde::Deserialize)]
+pub struct JwtSettings {
+    pub algorithm: JwtAlgorithm,
+    /// Shared secret for `HS256`.
+    pub secret: Option<Secret<String>>,
+    /// JWKS file holding the public keys for `RS256`/`ES256`.
+    pub jwks_path: Option<String>,
+    /// Expected `aud` claim, tokens without one are refused. Not checked when unset.
+    pub audience: Option<String>,
+    /// Claim carrying the username.
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+    #[serde(default, deserialize_with = "deserialize_number_from_string")]
+    pub leeway_seconds: u64
+}
+
+fn default_username_claim() -> String {
+    "sub".into()
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JwtAlgorithm {
+    HS256,
+    RS256,
+    ES256
+}
+
+/// What `AuthorMiddleware` does when the auth service cannot be reached.
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Let the request through if the same credentials were verified within
+    /// `open_grace_seconds`, reject it with 503 otherwise.
+    Open,
+    /// Reject the request with 503.
+    Closed
+}
+
+/// Which `storage::Storage` implementation backs uploaded media.
+#[derive(serde::Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageSettings {
+    Local {
+        root: String,
+        base_url: String
+    },
+    S3(S3Settings),
+    Memory
+}
+
+#[derive(serde::Deserialize)]
+pub struct S3Settings {
+    pub bucket: String,
+    pub region: String,
+    /// Custom endpoint for S3-compatible services such as MinIO.
+    pub endpoint: Option<String>,
+    pub access_key: String,
+    pub secret_key: Secret<String>,
+    #[serde(default)]
+    pub path_style: bool
+}
+
+/// Limits applied to every uploaded image before it reaches storage.
+#[derive(serde::Deserialize)]
+pub struct MediaSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_bytes: usize,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_width: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_height: u32,
+    /// Decoded size over encoded size; anything above is treated as a decompression bomb.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_decompression_ratio: u64,
+    /// Images, or animation frames, of fewer pixels skip the ratio check. Small flat graphics
+    /// legitimately compress far and can't expand into much anyway.
+    #[serde(default, deserialize_with = "deserialize_number_from_string")]
+    pub decompression_ratio_min_pixels: u64,
+    /// Longest edge, in pixels, of each resized copy generated for a post.
+    #[serde(default)]
+    pub variant_sizes: Vec<u32>,
+    /// Lossy encoder quality used for the variants, 0-100.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub variant_quality: u8,
+    /// Quality JPEG originals are re-encoded with once their metadata is stripped.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub reencode_quality: u8,
+    /// Exif fields, by tag name, copied over to the stored original. Everything else is dropped.
+    #[serde(default)]
+    pub keep_exif: Vec<String>,
+    pub accepted_formats: Vec<UploadFormat>,
+    /// Upper bound on frames in an animated GIF or WebP.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_frames: u32,
+    /// Videos get their own size limit, `max_bytes` applies to images.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_video_bytes: usize,
+    /// In seconds.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_video_duration: u32,
+    /// Whole multipart body of an upload, all its files together.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_request_bytes: usize
+}
+
+impl MediaSettings {
+    /// The most a single uploaded file may weigh, whatever it turns out to be.
+    pub fn max_upload_bytes(&self) -> usize {
+        self.max_bytes.max(self.max_video_bytes)
+    }
+}
+
+/// Background job retrying the storage outbox and removing objects no post references.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ReaperSettings {
+    pub enabled: bool,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub interval_seconds: u64,
+    /// Objects younger than this are never treated as orphans, uploads may still be in flight.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub grace_period_seconds: u64,
+    /// Outbox entries handled per run.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub batch_size: i64,
+    /// Failed outbox entries are retried with a growing delay, then left for an operator.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_attempts: i32
+}
+
+/// Uploads sent in chunks over several requests, see `routes::resumable`.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ResumableSettings {
+    /// Where the bytes of unfinished uploads are kept, on disk whatever the storage backend.
+    /// With several instances behind a load balancer it has to be a volume they all mount,
+    /// any of them may receive the next chunk.
+    pub directory: String,
+    /// Sessions not written to for this long are removed with their bytes.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub expiry_seconds: u64,
+    /// How long a chunk or a post holds its session at most. Another request can take it
+    /// over afterwards, so it has to outlast the slowest chunk.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub claim_seconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub cleanup_interval_seconds: u64
+}
+
+/// Uploads clients send straight to storage, see `routes::direct`.
+#[derive(serde::Deserialize)]
+pub struct DirectUploadSettings {
+    /// Signs the `/uploads/{token}` URLs handed out when the storage backend can't presign its own.
+    pub secret: Secret<String>,
+    /// How long an upload URL stays valid.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub expiry_seconds: u64
+}
+
+/// Signed URLs stored files are handed out under, presigned by the bucket on S3.
+#[derive(serde::Deserialize, Clone)]
+pub struct FileUrlSettings {
+    pub secret: Secret<String>,
+    /// URLs stay valid at least this long and at most twice as long. Expiries are rounded
+    /// up so that a file keeps the same URL for a while, which lets clients cache it.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub expiry_seconds: u64
+}
+
+/// Images transformed on request by `/img/{id}`, see `image_cache`.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ImageSettings {
+    /// Widths and heights that can be asked for, anything else is turned down so the number
+    /// of renditions per image stays bounded.
+    pub sizes: Vec<u32>,
+    /// Lossy encoder quality, 0-100.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub quality: u8,
+    /// Where transformed images are kept, on local disk whatever the storage backend.
+    pub cache_directory: String,
+    /// The least recently used images are dropped beyond this size.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub cache_max_bytes: u64
+}
+
+/// Image and video formats uploads can be sent in.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadFormat {
+    Png,
+    Jpeg,
+    /// Stored as-is, animations included.
+    Webp,
+    /// Stored as-is, animations included.
+    Gif,
+    /// Transcoded to JPEG, or PNG when it has transparency. AVIF is left out, there is no
+    /// decoder for it that builds without system libraries.
+    Heic,
+    /// H.264, VP9 or AV1 video, stored as-is.
+    Mp4,
+    /// VP8, VP9 or AV1 video, stored as-is.
+    Webm
 }
 
 #[derive(serde::Deserialize)]
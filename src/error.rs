@@ -0,0 +1,209 @@
+use std::fmt;
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::StatusCode;
+use actix_web::error::InternalError;
+use actix_web::{Error, HttpResponse, ResponseError};
+use serde::Serialize;
+use tracing_actix_web::RequestId;
+use validator::ValidationErrors;
+
+use crate::media::MediaError;
+use crate::storage::StorageError;
+
+
+/// Every handler error ends up here and leaves the service as a JSON body:
+///
+/// `{"code": "not_found", "message": "...", "request_id": "..."}`
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    Validation(ValidationErrors),
+    Media(MediaError),
+    Storage(StorageError),
+    Database(sqlx::Error),
+    ServiceUnavailable(String),
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<&'a ValidationErrors>,
+    request_id: Option<String>,
+}
+
+impl ApiError {
+    /// Stable, machine readable identifier of the error kind.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::Media(e) => e.code(),
+            ApiError::Storage(StorageError::NotFound(_)) => "not_found",
+            ApiError::Storage(_) => "storage_error",
+            ApiError::Database(_) => "database_error",
+            ApiError::ServiceUnavailable(_) => "service_unavailable",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn body(&self, request_id: Option<String>) -> String {
+        // Don't leak driver or backend details to clients
+        let message = match self {
+            ApiError::Storage(StorageError::NotFound(_)) => "resource not found".to_string(),
+            ApiError::Storage(_) => "failed to access storage".to_string(),
+            ApiError::Database(_) => "failed to access the database".to_string(),
+            ApiError::Media(MediaError::Io(_)) => "failed to read the upload".to_string(),
+            ApiError::Validation(_) => "request validation failed".to_string(),
+            e => e.to_string(),
+        };
+        let details = match self {
+            ApiError::Validation(errors) => Some(errors),
+            _ => None
+        };
+        serde_json::to_string(&ErrorBody {
+            code: self.code(),
+            message,
+            details,
+            request_id
+        }).expect("error bodies are always serializable")
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(m)
+            | ApiError::Unauthorized(m)
+            | ApiError::Forbidden(m)
+            | ApiError::NotFound(m)
+            | ApiError::Conflict(m)
+            | ApiError::PayloadTooLarge(m)
+            | ApiError::UnsupportedMediaType(m)
+            | ApiError::ServiceUnavailable(m)
+            | ApiError::Internal(m) => f.write_str(m),
+            ApiError::Validation(e) => write!(f, "validation failed: {}", e),
+            ApiError::Media(e) => write!(f, "{}", e),
+            ApiError::Storage(e) => write!(f, "{}", e),
+            ApiError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Media(MediaError::TooLarge { .. }) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::Media(MediaError::UnsupportedFormat(_))
+            | ApiError::Media(MediaError::UnsupportedCodec(_))
+            | ApiError::Media(MediaError::FormatMismatch { .. }) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Media(MediaError::Encode(_))
+            | ApiError::Media(MediaError::Io(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Media(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Storage(StorageError::NotFound(_)) => StatusCode::NOT_FOUND,
+            ApiError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("application/json")
+            .body(self.body(None))
+    }
+}
+
+/// Postgres' `lock_not_available`, raised by `NOWAIT` on rows another transaction holds.
+pub const LOCK_NOT_AVAILABLE: &str = "55P03";
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::RowNotFound => ApiError::NotFound("resource not found".into()),
+            e => ApiError::Database(e)
+        }
+    }
+}
+
+impl From<StorageError> for ApiError {
+    fn from(e: StorageError) -> Self {
+        ApiError::Storage(e)
+    }
+}
+
+impl From<MediaError> for ApiError {
+    fn from(e: MediaError) -> Self {
+        ApiError::Media(e)
+    }
+}
+
+impl From<ValidationErrors> for ApiError {
+    fn from(e: ValidationErrors) -> Self {
+        ApiError::Validation(e)
+    }
+}
+
+/// `ResponseError` has no access to the request, so the request id is filled in
+/// afterwards. Has to run inside `TracingLogger`, which assigns the id.
+///
+/// Errors returned by middleware (e.g. `Author`) never become a `ServiceResponse`
+/// in here, so they get their body swapped through an `InternalError` instead.
+pub fn attach_request_id<B>(
+    res: Result<ServiceResponse<B>, Error>,
+    request_id: Option<RequestId>
+) -> Result<ServiceResponse<BoxBody>, Error>
+where
+    B: MessageBody + 'static,
+{
+    let request_id = request_id.map(|id| id.to_string());
+
+    match res {
+        Ok(res) => {
+            let body = res.response().error()
+                .and_then(|e| e.as_error::<ApiError>())
+                .map(|e| e.body(request_id));
+
+            Ok(match body {
+                // Swap the body only, so the error stays attached for `TracingLogger`
+                Some(body) => res.map_body(|_, _| BoxBody::new(body)),
+                None => res.map_into_boxed_body()
+            })
+        }
+        Err(e) => match e.as_error::<ApiError>() {
+            Some(api_error) => {
+                let response = api_error.error_response()
+                    .set_body(BoxBody::new(api_error.body(request_id)));
+                Err(InternalError::from_response(api_error.to_string(), response).into())
+            }
+            None => Err(e)
+        }
+    }
+}
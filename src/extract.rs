@@ -0,0 +1,380 @@
+use std::ops::Deref;
+
+use actix_multipart::{Field, Multipart};
+use actix_web::{dev::Payload, http::header, web, Error, FromRequest, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use futures_util::TryStreamExt;
+use sha2::{Digest, Sha256};
+use tempfile::TempPath;
+use tokio::io::AsyncWriteExt;
+use validator::Validate;
+
+use crate::configuration::MediaSettings;
+use crate::error::ApiError;
+
+/// Text fields are captions and alt texts, anything bigger is not meant for us.
+const MAX_TEXT_BYTES: usize = 64 * 1024;
+
+
+/// Runs `validator` on whatever `E` extracts (`web::Json<T>`, `Form<T>`, ...)
+/// and rejects the request with a 422 listing the offending fields, e.g.
+/// `update: Validated<web::Json<PostUpdate>>`.
+pub struct Validated<E>(pub E);
+
+impl<E> Validated<E> {
+    pub fn into_inner(self) -> E {
+        self.0
+    }
+}
+
+impl<E> Deref for Validated<E> {
+    type Target = E;
+
+    fn deref(&self) -> &E {
+        &self.0
+    }
+}
+
+impl<E, T> FromRequest for Validated<E>
+where
+    E: FromRequest + Deref<Target = T> + 'static,
+    E::Error: Into<Error>,
+    E::Future: 'static,
+    T: Validate,
+{
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let inner = E::from_request(req, payload);
+
+        Box::pin(async move {
+            let inner = inner.await.map_err(|e| {
+                let e: Error = e.into();
+                // Json/Query/Path already answer with an `ApiError`, multipart does not
+                if e.as_error::<ApiError>().is_some() {
+                    return e
+                }
+                let message = e.to_string();
+                ApiError::BadRequest(
+                    if message.is_empty() { "malformed request body".to_string() } else { message }
+                ).into()
+            })?;
+
+            inner.validate().map_err(ApiError::Validation)?;
+
+            Ok(Validated(inner))
+        })
+    }
+}
+
+/// A file part of a `multipart/form-data` body, spooled to a temporary file.
+#[derive(Debug)]
+pub struct FormFile {
+    pub file_name: String,
+    pub content_type: String,
+    /// Deleted from disk when the `FormFile` is dropped.
+    pub path: TempPath,
+    pub size: u64,
+    /// Hex encoded SHA-256 of the content.
+    pub sha256: String
+}
+
+/// Every part of a `multipart/form-data` body, in the order they were sent, so repeated
+/// names (`img_file`, `img_file`, ...) all survive. A trailing `[]` on a name is dropped.
+#[derive(Debug, Default)]
+pub struct FormData {
+    texts: Vec<(String, String)>,
+    files: Vec<(String, FormFile)>
+}
+
+impl FormData {
+    /// All text values sent under `name`, in order.
+    pub fn take_texts(&mut self, name: &str) -> Vec<String> {
+        take(&mut self.texts, name)
+    }
+
+    /// The last text value sent under `name`.
+    pub fn take_text(&mut self, name: &str) -> Option<String> {
+        self.take_texts(name).pop()
+    }
+
+    /// All files sent under `name`, in order.
+    pub fn take_files(&mut self, name: &str) -> Vec<FormFile> {
+        take(&mut self.files, name)
+    }
+}
+
+fn take<T>(parts: &mut Vec<(String, T)>, name: &str) -> Vec<T> {
+    let (taken, rest) = std::mem::take(parts).into_iter()
+        .partition::<Vec<_>, _>(|(n, _)| n == name);
+    *parts = rest;
+    taken.into_iter().map(|(_, value)| value).collect()
+}
+
+/// Types built out of a `multipart/form-data` body by `Form`.
+pub trait FromForm: Sized {
+    /// Requests with more file parts are refused before the extra ones are read.
+    const MAX_FILES: usize;
+
+    fn from_form(form: FormData) -> Result<Self, ApiError>;
+}
+
+/// Reads a `multipart/form-data` body into `T`.
+///
+/// File parts are written to temporary files as they arrive and hashed on the way, so
+/// uploads never sit in memory whole. A file going over `media.max_upload_bytes()`, or a
+/// request over `media.max_request_bytes`, is refused as soon as the limit is crossed and
+/// whatever was written so far is removed.
+pub struct Form<T>(pub T);
+
+impl<T> Form<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Form<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: FromForm + 'static> FromRequest for Form<T> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let (max_file_bytes, max_request_bytes) = req.app_data::<web::Data<MediaSettings>>()
+            .map(|media| (media.max_upload_bytes() as u64, media.max_request_bytes as u64))
+            .unwrap_or((u64::MAX, u64::MAX));
+
+        // Don't read a byte of a body that announces it is too big
+        let content_length = req.headers().get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if content_length.is_some_and(|length| length > max_request_bytes) {
+            let error = request_too_large(max_request_bytes);
+            return Box::pin(async move { Err(error.into()) })
+        }
+
+        let mut multipart = Multipart::new(req.headers(), payload.take());
+
+        Box::pin(async move {
+            let mut form = FormData::default();
+            let mut budget = Budget { max: max_request_bytes, remaining: max_request_bytes };
+            while let Some(mut field) = multipart.try_next().await.map_err(malformed)? {
+                let name = field.name().trim_end_matches("[]").to_string();
+
+                let file_name = match field.content_disposition().get_filename() {
+                    Some(file_name) => file_name.to_string(),
+                    None => {
+                        let text = read_text(&mut field, &name, &mut budget).await?;
+                        form.texts.push((name, text));
+                        continue
+                    }
+                };
+                if form.files.len() == T::MAX_FILES {
+                    return Err(ApiError::PayloadTooLarge(
+                        format!("at most {} files are allowed per request", T::MAX_FILES)
+                    ).into())
+                }
+
+                let content_type = field.content_type().essence_str().to_string();
+                let (path, size, sha256) = spool(&mut field, &file_name, max_file_bytes, &mut budget).await?;
+                // Browsers send an empty part for a file input left blank
+                if size == 0 {
+                    continue
+                }
+                form.files.push((name, FormFile {
+                    file_name,
+                    content_type,
+                    path,
+                    size,
+                    sha256
+                }));
+            }
+
+            Ok(Form(T::from_form(form)?))
+        })
+    }
+}
+
+async fn read_text(field: &mut Field, name: &str, budget: &mut Budget) -> Result<String, ApiError> {
+    let mut data = Vec::new();
+    while let Some(chunk) = field.try_next().await.map_err(malformed)? {
+        budget.take(chunk.len())?;
+        if data.len() + chunk.len() > MAX_TEXT_BYTES {
+            return Err(ApiError::PayloadTooLarge(format!("field {} is too long", name)))
+        }
+        data.extend_from_slice(&chunk);
+    }
+    String::from_utf8(data)
+        .map_err(|_| ApiError::BadRequest(format!("field {} is not valid UTF-8", name)))
+}
+
+/// Write a file part to a temporary file, hashing it along the way.
+///
+/// On error the `TempPath` is dropped on the way out, which removes the partial file.
+async fn spool(
+    field: &mut Field,
+    file_name: &str,
+    limit: u64,
+    budget: &mut Budget
+) -> Result<(TempPath, u64, String), ApiError> {
+    let temp = web::block(|| tempfile::Builder::new().prefix("poster-upload-").tempfile())
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))?
+        .map_err(|e| ApiError::Internal(format!("failed to create a temporary file: {}", e)))?;
+    let (file, path) = temp.into_parts();
+    let mut file = tokio::fs::File::from_std(file);
+
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    while let Some(chunk) = field.try_next().await.map_err(malformed)? {
+        budget.take(chunk.len())?;
+        size += chunk.len() as u64;
+        if size > limit {
+            // The rest is not read, so the real size is unknown
+            return Err(ApiError::PayloadTooLarge(format!("{} is larger than {} bytes", file_name, limit)))
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await
+            .map_err(|e| ApiError::Internal(format!("failed to write a temporary file: {}", e)))?;
+    }
+    file.flush().await
+        .map_err(|e| ApiError::Internal(format!("failed to write a temporary file: {}", e)))?;
+
+    Ok((path, size, to_hex(&hasher.finalize())))
+}
+
+/// Hex encoded SHA-256 of the file at `path`. Blocks on the whole file.
+pub(crate) fn hash_file(path: &std::path::Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// What is left of `media.max_request_bytes` while a body is read.
+struct Budget {
+    max: u64,
+    remaining: u64
+}
+
+impl Budget {
+    fn take(&mut self, len: usize) -> Result<(), ApiError> {
+        self.remaining = self.remaining.checked_sub(len as u64)
+            .ok_or_else(|| request_too_large(self.max))?;
+        Ok(())
+    }
+}
+
+fn request_too_large(max: u64) -> ApiError {
+    ApiError::PayloadTooLarge(format!("request body is larger than {} bytes", max))
+}
+
+fn malformed(e: actix_multipart::MultipartError) -> ApiError {
+    ApiError::BadRequest(format!("malformed multipart body: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderMap, HeaderValue};
+    use actix_web::web::Bytes;
+    use futures_util::stream;
+
+    use super::*;
+
+    const BOUNDARY: &str = "poster-test-boundary";
+
+    /// A multipart body with one file part per entry of `files`.
+    fn multipart(files: &[&[u8]]) -> Multipart {
+        let mut body = Vec::new();
+        for (i, data) in files.iter().enumerate() {
+            body.extend_from_slice(format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"img_file\"; filename=\"{}.png\"\r\nContent-Type: image/png\r\n\r\n",
+                BOUNDARY, i
+            ).as_bytes());
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_str(&format!("multipart/form-data; boundary={}", BOUNDARY)).unwrap()
+        );
+        // Sent in small chunks, like a slow client would
+        let chunks: Vec<_> = body.chunks(7).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect();
+        Multipart::new(&headers, stream::iter(chunks))
+    }
+
+    fn budget(max: u64) -> Budget {
+        Budget { max, remaining: max }
+    }
+
+    #[test]
+    fn budget_refuses_going_over() {
+        let mut budget = budget(10);
+        budget.take(4).unwrap();
+        budget.take(6).unwrap();
+        assert!(matches!(budget.take(1), Err(ApiError::PayloadTooLarge(_))));
+    }
+
+    #[actix_web::test]
+    async fn spool_writes_and_hashes_the_part() {
+        let mut multipart = multipart(&[b"hello"]);
+        let mut field = multipart.try_next().await.unwrap().unwrap();
+        let mut budget = budget(1024);
+
+        let (path, size, sha256) = spool(&mut field, "0.png", 1024, &mut budget).await.unwrap();
+
+        assert_eq!(size, 5);
+        assert_eq!(sha256, "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+        assert_eq!(hash_file(&path).unwrap(), sha256);
+        assert_eq!(budget.remaining, 1024 - 5);
+    }
+
+    #[actix_web::test]
+    async fn spool_refuses_files_over_the_limit() {
+        let mut multipart = multipart(&[&[7; 100]]);
+        let mut field = multipart.try_next().await.unwrap().unwrap();
+
+        let result = spool(&mut field, "0.png", 99, &mut budget(1024)).await;
+        assert!(matches!(result, Err(ApiError::PayloadTooLarge(_))));
+    }
+
+    #[actix_web::test]
+    async fn spool_shares_the_budget_between_parts() {
+        let mut multipart = multipart(&[&[1; 60], &[2; 60]]);
+        let mut budget = budget(100);
+
+        let mut first = multipart.try_next().await.unwrap().unwrap();
+        spool(&mut first, "0.png", 80, &mut budget).await.unwrap();
+        drop(first);
+        let mut second = multipart.try_next().await.unwrap().unwrap();
+        let result = spool(&mut second, "1.png", 80, &mut budget).await;
+        assert!(matches!(result, Err(ApiError::PayloadTooLarge(_))));
+    }
+
+    #[test]
+    fn form_data_takes_parts_in_order() {
+        let mut form = FormData::default();
+        for (name, value) in [("alt_text", "a"), ("caption", "first"), ("alt_text", "b"), ("caption", "second")] {
+            form.texts.push((name.to_string(), value.to_string()));
+        }
+
+        assert_eq!(form.take_texts("alt_text"), ["a", "b"]);
+        assert!(form.take_texts("alt_text").is_empty());
+        assert_eq!(form.take_text("caption").as_deref(), Some("second"));
+        assert_eq!(form.take_text("missing"), None);
+    }
+}
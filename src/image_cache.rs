@@ -0,0 +1,95 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use moka::notification::RemovalCause;
+use moka::policy::EvictionPolicy;
+use moka::sync::Cache;
+
+use crate::configuration::ImageSettings;
+
+
+/// Transformed images on local disk, up to `cache_max_bytes` of them. The least recently
+/// used are removed first.
+///
+/// Entries are keyed by file name. Names start with the SHA-256 of the source image, or its
+/// id, and otherwise describe the transform, so an entry never goes stale.
+pub struct ImageCache {
+    directory: PathBuf,
+    entries: Cache<String, u64>
+}
+
+impl ImageCache {
+    /// Picks up what earlier runs left in the directory, oldest first.
+    pub fn open(settings: &ImageSettings) -> std::io::Result<Self> {
+        let directory = PathBuf::from(&settings.cache_directory);
+        std::fs::create_dir_all(&directory)?;
+
+        let removed_from = directory.clone();
+        let entries = Cache::builder()
+            .max_capacity(settings.cache_max_bytes)
+            .weigher(|_, size: &u64| (*size).try_into().unwrap_or(u32::MAX))
+            .eviction_policy(EvictionPolicy::lru())
+            .eviction_listener(move |name: Arc<String>, _, cause| {
+                // A replaced entry shares its file with the new one
+                if cause != RemovalCause::Replaced {
+                    let _ = std::fs::remove_file(removed_from.join(name.as_str()));
+                }
+            })
+            .build();
+
+        let mut existing = Vec::new();
+        for entry in std::fs::read_dir(&directory)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.ends_with(".tmp") {
+                // Never made it into the cache
+                let _ = std::fs::remove_file(entry.path());
+            } else if metadata.is_file() {
+                existing.push((metadata.accessed().or_else(|_| metadata.modified())?, name, metadata.len()));
+            }
+        }
+        existing.sort();
+        for (_, name, size) in existing {
+            entries.insert(name, size);
+        }
+
+        Ok(ImageCache { directory, entries })
+    }
+
+    /// The cached image named `name`, `None` on a miss.
+    pub async fn get(&self, name: &str) -> std::io::Result<Option<Vec<u8>>> {
+        if !self.entries.contains_key(name) {
+            return Ok(None)
+        }
+        match tokio::fs::read(self.path(name)).await {
+            Ok(data) => {
+                // Counts as a use
+                self.entries.get(name);
+                Ok(Some(data))
+            }
+            // Evicted in the meantime, or removed from under us
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.entries.invalidate(name);
+                Ok(None)
+            }
+            Err(e) => Err(e)
+        }
+    }
+
+    pub async fn insert(&self, name: &str, data: &[u8]) -> std::io::Result<()> {
+        // Written aside then renamed, so readers never see a partial file
+        let temp = self.path(&format!("{}.{}.tmp", name, uuid::Uuid::new_v4()));
+        tokio::fs::write(&temp, data).await?;
+        if let Err(e) = tokio::fs::rename(&temp, self.path(name)).await {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(e)
+        }
+        self.entries.insert(name.to_string(), data.len() as u64);
+        Ok(())
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        Path::new(&self.directory).join(name)
+    }
+}
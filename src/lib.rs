@@ -7,4 +7,13 @@ pub mod configuration;
 pub mod telemetry;
 pub mod routes;
 pub mod models;
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod storage;
+pub mod error;
+pub mod extract;
+pub mod image_cache;
+pub mod media;
+pub mod outbox;
+pub mod placeholders;
+pub mod resumable;
+pub mod signing;
\ No newline at end of file
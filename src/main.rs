@@ -1,6 +1,7 @@
 use std::net::TcpListener;
 use sqlx::postgres::PgPoolOptions;
 use poster::auth::AuthClient;
+use poster::{outbox, resumable, storage};
 
 use poster::configuration::get_configuration;
 use poster::startup::run;
@@ -24,7 +25,25 @@ async fn main() -> std::io::Result<()> {
         .connect_timeout(std::time::Duration::from_secs(2))
         .connect_lazy_with(configuration.database.with_db());
 
-    let auth_client = AuthClient::new(configuration.auth_client.base_url);
+    let auth_client = AuthClient::new(&configuration.auth_client)
+        .expect("Failed to initialize auth client");
+
+    let storage = storage::build(&configuration.storage, &configuration.file_urls)
+        .expect("Failed to initialize storage backend");
+
+    // Finishes storage work requests left behind and removes files nothing points to
+    if configuration.reaper.enabled {
+        actix_web::rt::spawn(outbox::run_reaper(
+            connection_pool.clone(),
+            storage.clone(),
+            configuration.reaper.clone()
+        ));
+    }
+
+    actix_web::rt::spawn(resumable::run_cleanup(
+        connection_pool.clone(),
+        configuration.resumable.clone()
+    ));
 
     let address = format!(
         "{}:{}",
@@ -33,6 +52,16 @@ async fn main() -> std::io::Result<()> {
     let listener = TcpListener::bind(address)
         .expect("Failed to bind address");
 
-    run(listener, connection_pool, auth_client)?.await
+    run(
+        listener,
+        connection_pool,
+        auth_client,
+        storage,
+        configuration.media,
+        configuration.resumable,
+        configuration.direct_uploads,
+        configuration.file_urls,
+        configuration.images
+    )?.await
 
 }
\ No newline at end of file
@@ -0,0 +1,197 @@
+use super::MediaError;
+
+
+/// Drop the `EXIF` and `XMP ` chunks of a WebP file, leaving the image data untouched.
+pub fn strip_webp(data: &[u8]) -> Result<Vec<u8>, MediaError> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err(corrupt("not a RIFF/WebP file"))
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(b"RIFF\0\0\0\0WEBP");
+
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let fourcc = &data[pos..pos + 4];
+        let size = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        // Chunks are padded to an even length, some encoders leave out the final pad byte
+        let end = (pos + 8 + size + (size & 1)).min(data.len());
+        if pos + 8 + size > data.len() {
+            return Err(corrupt("truncated WebP chunk"))
+        }
+
+        match fourcc {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" if size >= 1 => {
+                let start = out.len();
+                out.extend_from_slice(&data[pos..end]);
+                // Clear the "has Exif" and "has XMP" flags
+                out[start + 8] &= !(0x08 | 0x04);
+            }
+            _ => out.extend_from_slice(&data[pos..end]),
+        }
+        pos = end;
+    }
+
+    if out.len() % 2 == 1 {
+        out.push(0);
+    }
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Ok(out)
+}
+
+/// Drop comment and application extensions, except the looping ones, from a GIF.
+pub fn strip_gif(data: &[u8]) -> Result<Vec<u8>, MediaError> {
+    if data.len() < 13 || !matches!(&data[0..6], b"GIF87a" | b"GIF89a") {
+        return Err(corrupt("not a GIF file"))
+    }
+
+    // Header, logical screen descriptor and global color table
+    let mut pos = 13 + color_table_len(data[10]);
+    let mut out = data.get(..pos).ok_or_else(|| corrupt("truncated GIF header"))?.to_vec();
+
+    loop {
+        match data.get(pos) {
+            // Image descriptor, optional local color table, LZW code size and image data
+            Some(0x2C) => {
+                let packed = *data.get(pos + 9).ok_or_else(|| corrupt("truncated image descriptor"))?;
+                let data_start = pos + 10 + color_table_len(packed) + 1;
+                let end = skip_sub_blocks(data, data_start)?;
+                out.extend_from_slice(&data[pos..end]);
+                pos = end;
+            }
+            Some(0x21) => {
+                let label = *data.get(pos + 1).ok_or_else(|| corrupt("truncated extension"))?;
+                let end = skip_sub_blocks(data, pos + 2)?;
+                let keep = match label {
+                    0xFE => false,
+                    0xFF => matches!(data.get(pos + 3..pos + 14), Some(b"NETSCAPE2.0") | Some(b"ANIMEXTS1.0")),
+                    _ => true
+                };
+                if keep {
+                    out.extend_from_slice(&data[pos..end]);
+                }
+                pos = end;
+            }
+            Some(0x3B) => {
+                out.push(0x3B);
+                return Ok(out)
+            }
+            _ => return Err(corrupt("unexpected GIF block"))
+        }
+    }
+}
+
+fn color_table_len(packed: u8) -> usize {
+    if packed & 0x80 == 0 {
+        0
+    } else {
+        3 * (1 << ((packed & 0x07) + 1))
+    }
+}
+
+/// Position right after the terminating zero-length sub-block starting at `pos`.
+fn skip_sub_blocks(data: &[u8], mut pos: usize) -> Result<usize, MediaError> {
+    loop {
+        let len = *data.get(pos).ok_or_else(|| corrupt("truncated GIF data"))? as usize;
+        pos += 1 + len;
+        if len == 0 {
+            return Ok(pos)
+        }
+    }
+}
+
+fn corrupt(reason: &str) -> MediaError {
+    MediaError::Corrupt(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(fourcc: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = fourcc.to_vec();
+        data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        data.extend_from_slice(payload);
+        if payload.len() % 2 == 1 {
+            data.push(0);
+        }
+        data
+    }
+
+    fn riff(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = chunks.concat();
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+        data.extend_from_slice(b"WEBP");
+        data.extend_from_slice(&body);
+        data
+    }
+
+    #[test]
+    fn strip_webp_drops_metadata_chunks() {
+        // Has Exif and XMP, along with the alpha flag
+        let vp8x = chunk(b"VP8X", &[0x08 | 0x04 | 0x10, 0, 0, 0, 1, 0, 0, 1, 0, 0]);
+        let image = chunk(b"VP8L", b"pixels!");
+        let webp = riff(&[
+            vp8x.clone(),
+            image.clone(),
+            chunk(b"EXIF", b"exif data"),
+            chunk(b"XMP ", b"<xmp/>")
+        ]);
+
+        let stripped = strip_webp(&webp).unwrap();
+
+        let mut expected_vp8x = vp8x;
+        expected_vp8x[8] = 0x10;
+        assert_eq!(stripped, riff(&[expected_vp8x, image]));
+    }
+
+    #[test]
+    fn strip_webp_keeps_files_without_metadata() {
+        let webp = riff(&[chunk(b"VP8 ", b"odd")]);
+        assert_eq!(strip_webp(&webp).unwrap(), webp);
+    }
+
+    #[test]
+    fn strip_webp_rejects_truncated_chunks() {
+        let mut webp = riff(&[chunk(b"VP8L", b"pixels")]);
+        webp.truncate(webp.len() - 2);
+        assert!(matches!(strip_webp(&webp), Err(MediaError::Corrupt(_))));
+        assert!(matches!(strip_webp(b"RIFF\0\0\0\0WAVE"), Err(MediaError::Corrupt(_))));
+    }
+
+    /// 1x1 GIF with a two color global table, `blocks` between the header and the image.
+    fn gif(blocks: &[&[u8]]) -> Vec<u8> {
+        let mut data = b"GIF89a\x01\0\x01\0\x80\0\0".to_vec();
+        data.extend_from_slice(&[0, 0, 0, 255, 255, 255]);
+        for block in blocks {
+            data.extend_from_slice(block);
+        }
+        data.extend_from_slice(GIF_IMAGE);
+        data.push(0x3B);
+        data
+    }
+
+    const GIF_IMAGE: &[u8] = b"\x2C\0\0\0\0\x01\0\x01\0\0\x02\x02\x44\x01\0";
+    const GRAPHIC_CONTROL: &[u8] = b"\x21\xF9\x04\0\x0A\0\0\0";
+    const LOOPING: &[u8] = b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\0\0\0";
+    const COMMENT: &[u8] = b"\x21\xFE\x05hello\x03abc\0";
+    const XMP: &[u8] = b"\x21\xFF\x0BXMP DataXMP\x04<x/>\0";
+
+    #[test]
+    fn strip_gif_drops_comments_and_foreign_extensions() {
+        let stripped = strip_gif(&gif(&[LOOPING, COMMENT, GRAPHIC_CONTROL, XMP])).unwrap();
+        assert_eq!(stripped, gif(&[LOOPING, GRAPHIC_CONTROL]));
+        image::load_from_memory(&stripped).unwrap();
+    }
+
+    #[test]
+    fn strip_gif_rejects_truncated_files() {
+        let data = gif(&[COMMENT]);
+        assert!(matches!(strip_gif(&data[..data.len() - 1]), Err(MediaError::Corrupt(_))));
+        assert!(matches!(strip_gif(&data[..20]), Err(MediaError::Corrupt(_))));
+        assert!(matches!(strip_gif(b"GIF90a"), Err(MediaError::Corrupt(_))));
+    }
+}
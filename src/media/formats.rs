@@ -0,0 +1,224 @@
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageFormat, RgbImage, RgbaImage};
+
+use crate::configuration::{MediaSettings, UploadFormat};
+use super::{check_size, Inspected, Metadata, MediaError};
+
+
+impl UploadFormat {
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        match mime {
+            "image/png" => Some(UploadFormat::Png),
+            "image/jpeg" => Some(UploadFormat::Jpeg),
+            "image/webp" => Some(UploadFormat::Webp),
+            "image/gif" => Some(UploadFormat::Gif),
+            "image/heic" | "image/heif" => Some(UploadFormat::Heic),
+            "video/mp4" => Some(UploadFormat::Mp4),
+            "video/webm" => Some(UploadFormat::Webm),
+            _ => None
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            UploadFormat::Png => "image/png",
+            UploadFormat::Jpeg => "image/jpeg",
+            UploadFormat::Webp => "image/webp",
+            UploadFormat::Gif => "image/gif",
+            UploadFormat::Heic => "image/heic",
+            UploadFormat::Mp4 => "video/mp4",
+            UploadFormat::Webm => "video/webm",
+        }
+    }
+
+    pub fn is_video(&self) -> bool {
+        matches!(self, UploadFormat::Mp4 | UploadFormat::Webm)
+    }
+
+    /// `None` for formats the `image` crate can't read.
+    pub fn image_format(&self) -> Option<ImageFormat> {
+        match self {
+            UploadFormat::Png => Some(ImageFormat::Png),
+            UploadFormat::Jpeg => Some(ImageFormat::Jpeg),
+            UploadFormat::Webp => Some(ImageFormat::WebP),
+            UploadFormat::Gif => Some(ImageFormat::Gif),
+            UploadFormat::Heic | UploadFormat::Mp4 | UploadFormat::Webm => None,
+        }
+    }
+}
+
+/// Tell the format from the file signature.
+pub fn sniff(data: &[u8]) -> Option<UploadFormat> {
+    // HEIF-based formats all start with an `ftyp` box, the brands say which codec is inside
+    if data.get(4..8) == Some(b"ftyp") {
+        let size = u32::from_be_bytes(data.get(0..4)?.try_into().ok()?) as usize;
+        let ftyp = data.get(8..size.min(data.len()))?;
+        // Major brand, minor version, then the compatible brands
+        let brands: Vec<&[u8]> = ftyp.chunks_exact(4)
+            .enumerate()
+            .filter(|(i, _)| *i != 1)
+            .map(|(_, brand)| brand)
+            .collect();
+        // Not supported, but AVIF files list `mif1` too and must not pass for HEIC
+        if brands.iter().any(|b| matches!(*b, b"avif" | b"avis")) {
+            return None
+        }
+        if brands.iter().any(|b| matches!(*b, b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" | b"mif1" | b"msf1")) {
+            return Some(UploadFormat::Heic)
+        }
+        if brands.iter().any(|b| matches!(*b, b"isom" | b"iso2" | b"iso4" | b"iso5" | b"iso6" | b"mp41" | b"mp42" | b"avc1" | b"av01" | b"dash")) {
+            return Some(UploadFormat::Mp4)
+        }
+        return None
+    }
+
+    // EBML header, WebM is the Matroska profile with `webm` as its DocType
+    if data.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        let header = data.get(4..4 + ebml_header_len(&data[4..])?)?;
+        return header.windows(4).any(|w| w == b"webm").then_some(UploadFormat::Webm)
+    }
+
+    match image::guess_format(data).ok()? {
+        ImageFormat::Png => Some(UploadFormat::Png),
+        ImageFormat::Jpeg => Some(UploadFormat::Jpeg),
+        ImageFormat::WebP => Some(UploadFormat::Webp),
+        ImageFormat::Gif => Some(UploadFormat::Gif),
+        _ => None
+    }
+}
+
+/// Length of the EBML header body plus its size field, which starts `data`.
+fn ebml_header_len(data: &[u8]) -> Option<usize> {
+    // Variable length integer, the leading zero bits of its first byte tell how many bytes follow
+    let first = *data.first()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 {
+        return None
+    }
+    let size = data.get(1..len)?.iter()
+        .fold((first as u64) & (0xFF >> len), |size, byte| size << 8 | *byte as u64);
+    // Real headers are a few dozen bytes, don't go looking through a whole file
+    (size <= 256).then_some(len + size as usize)
+}
+
+/// Decode the primary image of a HEIC file.
+///
+/// The decoder applies the container's rotation and mirroring itself, which take
+/// precedence over any Exif orientation, so nothing is left to rotate afterwards.
+pub fn decode_heic(data: &[u8], settings: &MediaSettings) -> Result<Inspected, MediaError> {
+    let corrupt = |e: heic_decoder::HeifError| MediaError::Corrupt(e.to_string());
+
+    let probe = heic_decoder::probe(data).map_err(corrupt)?;
+    let (width, height) = (probe.width as u32, probe.height as u32);
+    check_size(data, width, height, width as u64 * height as u64 * 4, settings)?;
+
+    let decoded = heic_decoder::decode(data).map_err(corrupt)?;
+    let (w, h) = (decoded.width as u32, decoded.height as u32);
+    let image = if decoded.alpha.is_some() {
+        let rgba = decoded.to_rgba8().map_err(corrupt)?;
+        RgbaImage::from_raw(w, h, rgba.data).map(DynamicImage::ImageRgba8)
+    } else {
+        let rgb = decoded.to_rgb8().map_err(corrupt)?;
+        RgbImage::from_raw(w, h, rgb.data).map(DynamicImage::ImageRgb8)
+    }.ok_or_else(|| MediaError::Corrupt("decoded HEIC has an unexpected size".into()))?;
+
+    Ok(Inspected {
+        format: UploadFormat::Heic,
+        image,
+        metadata: Metadata {
+            orientation: Orientation::NoTransforms,
+            exif: decoded.metadata.exif,
+            icc_profile: decoded.metadata.icc_profile
+        },
+        animated: false
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn encoded(format: ImageFormat) -> Vec<u8> {
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(2, 2))
+            .write_to(&mut Cursor::new(&mut data), format)
+            .unwrap();
+        data
+    }
+
+    /// An `ftyp` box with `major` as its major brand and `compatible` after it.
+    fn ftyp(major: &[u8; 4], compatible: &[&[u8; 4]]) -> Vec<u8> {
+        let size = 16 + 4 * compatible.len();
+        let mut data = (size as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(b"ftyp");
+        data.extend_from_slice(major);
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        for brand in compatible {
+            data.extend_from_slice(*brand);
+        }
+        // Whatever box comes next
+        data.extend_from_slice(b"\0\0\0\x08free");
+        data
+    }
+
+    /// An EBML header carrying `doc_type`.
+    fn ebml(doc_type: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x42, 0x82, 0x80 | doc_type.len() as u8];
+        body.extend_from_slice(doc_type);
+        let mut data = vec![0x1A, 0x45, 0xDF, 0xA3, 0x80 | body.len() as u8];
+        data.extend_from_slice(&body);
+        data
+    }
+
+    #[test]
+    fn sniffs_images_by_signature() {
+        assert_eq!(sniff(&encoded(ImageFormat::Png)), Some(UploadFormat::Png));
+        assert_eq!(sniff(&encoded(ImageFormat::Jpeg)), Some(UploadFormat::Jpeg));
+        assert_eq!(sniff(&encoded(ImageFormat::Gif)), Some(UploadFormat::Gif));
+        assert_eq!(sniff(&encoded(ImageFormat::WebP)), Some(UploadFormat::Webp));
+    }
+
+    #[test]
+    fn riff_needs_the_webp_form_type() {
+        let mut wav = b"RIFF\x24\0\0\0WAVEfmt ".to_vec();
+        wav.resize(44, 0);
+        assert_eq!(sniff(&wav), None);
+    }
+
+    #[test]
+    fn sniffs_iso_bmff_brands() {
+        assert_eq!(sniff(&ftyp(b"heic", &[b"mif1", b"heic"])), Some(UploadFormat::Heic));
+        // Only the compatible brands tell
+        assert_eq!(sniff(&ftyp(b"XXXX", &[b"mif1"])), Some(UploadFormat::Heic));
+        assert_eq!(sniff(&ftyp(b"isom", &[b"isom", b"avc1"])), Some(UploadFormat::Mp4));
+        assert_eq!(sniff(&ftyp(b"XXXX", &[b"mp42"])), Some(UploadFormat::Mp4));
+        assert_eq!(sniff(&ftyp(b"qt  ", &[b"qt  "])), None);
+    }
+
+    #[test]
+    fn avif_does_not_pass_for_heic() {
+        assert_eq!(sniff(&ftyp(b"avif", &[b"mif1", b"miaf"])), None);
+        assert_eq!(sniff(&ftyp(b"mif1", &[b"avif"])), None);
+    }
+
+    #[test]
+    fn minor_version_is_not_a_brand() {
+        let mut data = ftyp(b"XXXX", &[]);
+        data[12..16].copy_from_slice(b"heic");
+        assert_eq!(sniff(&data), None);
+    }
+
+    #[test]
+    fn truncated_ftyp_is_not_recognized() {
+        assert_eq!(sniff(b"\0\0\0\x18ftyp"), None);
+    }
+
+    #[test]
+    fn sniffs_webm_by_doc_type() {
+        assert_eq!(sniff(&ebml(b"webm")), Some(UploadFormat::Webm));
+        assert_eq!(sniff(&ebml(b"matroska")), None);
+        assert_eq!(sniff(&[0x1A, 0x45, 0xDF, 0xA3]), None);
+    }
+}
@@ -0,0 +1,239 @@
+use std::io::Cursor;
+
+use exif::experimental::Writer;
+use exif::{In, Reader, Tag};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageEncoder, ImageFormat};
+
+use super::variants::encode_webp;
+use super::MediaError;
+
+
+/// What the decoder found next to the pixels.
+pub struct Metadata {
+    pub orientation: Orientation,
+    /// Raw TIFF-structured Exif block.
+    pub exif: Option<Vec<u8>>,
+    /// Color profile, kept on purpose: dropping it shifts colors and it says nothing about the user.
+    pub icc_profile: Option<Vec<u8>>
+}
+
+impl Metadata {
+    pub fn read(decoder: &mut impl ImageDecoder) -> Self {
+        // Broken metadata is not a reason to turn an image down, it is about to be dropped anyway
+        Metadata {
+            orientation: decoder.orientation().unwrap_or(Orientation::NoTransforms),
+            exif: decoder.exif_metadata().ok().flatten(),
+            icc_profile: decoder.icc_profile().ok().flatten()
+        }
+    }
+}
+
+/// Encode `image` again so none of the Exif, XMP and IPTC blocks of the upload survive.
+///
+/// `image` is expected to have its orientation applied already. Exif fields named in
+/// `keep_exif` (e.g. `Copyright`, `Artist`) are written back, `Orientation` never is.
+pub fn reencode(
+    image: &DynamicImage,
+    format: ImageFormat,
+    metadata: &Metadata,
+    keep_exif: &[String],
+    quality: u8
+) -> Result<Vec<u8>, MediaError> {
+    let exif = match &metadata.exif {
+        Some(exif) if !keep_exif.is_empty() => filter_exif(exif, keep_exif),
+        _ => None
+    };
+
+    let mut encoded = Vec::new();
+    match format {
+        ImageFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut encoded, quality);
+            write(image, encoder, metadata, exif)?
+        }
+        ImageFormat::Png => write(image, PngEncoder::new(&mut encoded), metadata, exif)?,
+        // Only still images end up here. The encoder takes no metadata, so no Exif is kept
+        ImageFormat::WebP => encoded = encode_webp(image, quality)?,
+        format => return Err(MediaError::Encode(format!("cannot re-encode {:?}", format)))
+    }
+
+    Ok(encoded)
+}
+
+fn write(
+    image: &DynamicImage,
+    mut encoder: impl ImageEncoder,
+    metadata: &Metadata,
+    exif: Option<Vec<u8>>
+) -> Result<(), MediaError> {
+    if let Some(icc_profile) = &metadata.icc_profile {
+        encoder.set_icc_profile(icc_profile.clone())
+            .map_err(|e| MediaError::Encode(e.to_string()))?;
+    }
+    if let Some(exif) = exif {
+        encoder.set_exif_metadata(exif)
+            .map_err(|e| MediaError::Encode(e.to_string()))?;
+    }
+    image.write_with_encoder(encoder)
+        .map_err(|e| MediaError::Encode(e.to_string()))
+}
+
+/// Rebuild the Exif block with only the allowed fields of the primary image.
+/// Returns `None` when nothing is left, or the block can't be parsed.
+fn filter_exif(raw: &[u8], keep: &[String]) -> Option<Vec<u8>> {
+    let exif = Reader::new().read_raw(raw.to_vec()).ok()?;
+
+    let kept: Vec<_> = exif.fields()
+        .filter(|f| f.ifd_num == In::PRIMARY && f.tag != Tag::Orientation)
+        .filter(|f| keep.iter().any(|name| *name == f.tag.to_string()))
+        .collect();
+    if kept.is_empty() {
+        return None
+    }
+
+    let mut writer = Writer::new();
+    for field in kept {
+        writer.push_field(field);
+    }
+    let mut out = Cursor::new(Vec::new());
+    match writer.write(&mut out, exif.little_endian()) {
+        Ok(()) => Some(out.into_inner()),
+        Err(e) => {
+            tracing::warn!("Dropping Exif fields that could not be written back: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use exif::{Field, Value};
+    use image::{GenericImageView, Rgb, RgbImage};
+    use super::*;
+    use crate::media::{decode_stored, process};
+    use crate::media::tests::settings;
+
+    fn ascii(tag: Tag, text: &str) -> Field {
+        Field { tag, ifd_num: In::PRIMARY, value: Value::Ascii(vec![text.as_bytes().to_vec()]) }
+    }
+
+    /// Exif the way a phone writes it: who, where, and which way up.
+    fn exif(orientation: u16) -> Vec<u8> {
+        let fields = [
+            ascii(Tag::Artist, "Alice"),
+            ascii(Tag::Copyright, "CC-BY"),
+            Field { tag: Tag::Orientation, ifd_num: In::PRIMARY, value: Value::Short(vec![orientation]) },
+            ascii(Tag::GPSLatitudeRef, "N")
+        ];
+        let mut writer = Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut out = Cursor::new(Vec::new());
+        writer.write(&mut out, false).unwrap();
+        out.into_inner()
+    }
+
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![0xff, marker];
+        data.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    /// 32x16, red on the left and blue on the right, with Exif, XMP and IPTC segments.
+    fn jpeg(orientation: u16) -> Vec<u8> {
+        let image = RgbImage::from_fn(32, 16, |x, _| if x < 16 { Rgb([255, 0, 0]) } else { Rgb([0, 0, 255]) });
+        let mut encoded = Vec::new();
+        JpegEncoder::new_with_quality(&mut encoded, 95).encode_image(&image).unwrap();
+
+        let mut data = encoded[..2].to_vec();
+        data.extend(segment(0xe1, &[b"Exif\0\0".as_slice(), &exif(orientation)].concat()));
+        data.extend(segment(0xe1, b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta>GPS</x:xmpmeta>"));
+        data.extend(segment(0xed, b"Photoshop 3.0\08BIM\x04\x04\0\0\0\0\0\x05\x1c\x02\x5a\0\x01X"));
+        data.extend_from_slice(&encoded[2..]);
+        data
+    }
+
+    fn contains(data: &[u8], needle: &[u8]) -> bool {
+        data.windows(needle.len()).any(|window| window == needle)
+    }
+
+    fn stored_exif(data: &[u8]) -> Option<exif::Exif> {
+        Reader::new().read_from_container(&mut Cursor::new(data)).ok()
+    }
+
+    fn is_red(pixel: image::Rgba<u8>) -> bool {
+        pixel[0] > 200 && pixel[2] < 60
+    }
+
+    #[test]
+    fn jpeg_uploads_lose_exif_xmp_and_iptc() {
+        let data = jpeg(1);
+        assert!(stored_exif(&data).is_some());
+
+        let processed = process(&data, "image/jpeg", &settings()).unwrap();
+
+        assert!(stored_exif(&processed.original).is_none());
+        assert!(!contains(&processed.original, b"Exif\0\0"));
+        assert!(!contains(&processed.original, b"http://ns.adobe.com/xap/1.0/"));
+        assert!(!contains(&processed.original, b"Photoshop 3.0"));
+        assert!(!contains(&processed.original, b"Alice"));
+    }
+
+    #[test]
+    fn png_uploads_lose_exif() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([10, 20, 30])));
+        let mut data = Vec::new();
+        write(&image, PngEncoder::new(&mut data), &Metadata {
+            orientation: Orientation::NoTransforms,
+            exif: None,
+            icc_profile: None
+        }, Some(exif(1))).unwrap();
+        assert!(stored_exif(&data).is_some());
+
+        let processed = process(&data, "image/png", &settings()).unwrap();
+
+        assert!(stored_exif(&processed.original).is_none());
+        assert!(!contains(&processed.original, b"eXIf"));
+        assert!(!contains(&processed.original, b"Alice"));
+    }
+
+    #[test]
+    fn keep_exif_writes_back_only_the_allowed_fields() {
+        let mut settings = settings();
+        settings.keep_exif = vec!["Copyright".into(), "Orientation".into()];
+
+        let processed = process(&jpeg(6), "image/jpeg", &settings).unwrap();
+
+        let exif = stored_exif(&processed.original).expect("the copyright is kept");
+        let tags: Vec<_> = exif.fields().map(|f| f.tag).collect();
+        assert_eq!(tags, vec![Tag::Copyright]);
+        assert_eq!(exif.get_field(Tag::Copyright, In::PRIMARY).unwrap().display_value().to_string(), "\"CC-BY\"");
+        assert!(!contains(&processed.original, b"http://ns.adobe.com/xap/1.0/"));
+        assert!(!contains(&processed.original, b"Photoshop 3.0"));
+    }
+
+    #[test]
+    fn filter_exif_returns_nothing_without_allowed_fields() {
+        assert!(filter_exif(&exif(1), &["Make".into()]).is_none());
+        assert!(filter_exif(&exif(6), &["Orientation".into()]).is_none());
+        assert!(filter_exif(b"not exif", &["Artist".into()]).is_none());
+    }
+
+    #[test]
+    fn orientation_is_baked_into_the_pixels() {
+        // 6 is rotated 90 degrees clockwise, the red left half ends up on top
+        let processed = process(&jpeg(6), "image/jpeg", &settings()).unwrap();
+        assert_eq!((processed.info.width, processed.info.height), (16, 32));
+
+        let stored = decode_stored(&processed.original).unwrap();
+        assert_eq!(stored.dimensions(), (16, 32));
+        assert!(is_red(stored.get_pixel(8, 4)));
+        assert!(!is_red(stored.get_pixel(8, 28)));
+        // Nothing is left to rotate it a second time
+        assert!(stored_exif(&processed.original).is_none());
+    }
+}
@@ -0,0 +1,480 @@
+use std::fmt;
+use std::io::Cursor;
+use std::path::Path;
+use std::time::Duration;
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::metadata::Orientation;
+use image::{AnimationDecoder, DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageReader, Limits};
+
+use crate::configuration::{MediaSettings, UploadFormat};
+
+mod container;
+mod formats;
+mod metadata;
+mod placeholder;
+mod transform;
+mod variants;
+mod video;
+
+pub use formats::sniff;
+pub use metadata::{reencode, Metadata};
+pub use placeholder::{placeholder, Placeholder};
+pub use transform::{transform, Fit, OutputFormat, Transform};
+pub use variants::{render, RenderedVariant};
+pub use video::{ProcessedVideo, VideoInfo};
+
+
+/// The image as it is stored, which is not necessarily the format it was uploaded in.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageInfo {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32
+}
+
+impl ImageInfo {
+    pub fn content_type(&self) -> &'static str {
+        self.format.to_mime_type()
+    }
+
+    pub fn extension(&self) -> &'static str {
+        self.format.extensions_str()[0]
+    }
+}
+
+#[derive(Debug)]
+pub enum MediaError {
+    TooLarge { size: usize, max: usize },
+    UnsupportedFormat(String),
+    FormatMismatch { declared: String, actual: &'static str },
+    DimensionsTooLarge { width: u32, height: u32 },
+    DecompressionBomb { ratio: u64 },
+    TooManyFrames { max: u32 },
+    UnsupportedCodec(String),
+    TooLong { duration: Duration, max: Duration },
+    Corrupt(String),
+    InvalidVideo(String),
+    /// The spooled upload could not be read back.
+    Io(std::io::Error),
+    /// Producing a variant failed, which is on us rather than on the upload.
+    Encode(String)
+}
+
+impl MediaError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            MediaError::TooLarge { .. } => "file_too_large",
+            MediaError::UnsupportedFormat(_) => "unsupported_format",
+            MediaError::FormatMismatch { .. } => "format_mismatch",
+            MediaError::DimensionsTooLarge { .. } => "dimensions_too_large",
+            MediaError::DecompressionBomb { .. } => "decompression_bomb",
+            MediaError::TooManyFrames { .. } => "too_many_frames",
+            MediaError::UnsupportedCodec(_) => "unsupported_codec",
+            MediaError::TooLong { .. } => "video_too_long",
+            MediaError::Corrupt(_) => "corrupt_image",
+            MediaError::InvalidVideo(_) => "corrupt_video",
+            MediaError::Io(_) => "read_failed",
+            MediaError::Encode(_) => "encoding_failed",
+        }
+    }
+}
+
+impl fmt::Display for MediaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaError::TooLarge { size, max } =>
+                write!(f, "file is {} bytes, at most {} are allowed", size, max),
+            MediaError::UnsupportedFormat(format) =>
+                write!(f, "{} is not a supported image format", format),
+            MediaError::FormatMismatch { declared, actual } =>
+                write!(f, "file was sent as {} but contains {}", declared, actual),
+            MediaError::DimensionsTooLarge { width, height } =>
+                write!(f, "image is {}x{} pixels, which exceeds the allowed dimensions", width, height),
+            MediaError::DecompressionBomb { ratio } =>
+                write!(f, "image decodes to {} times its file size", ratio),
+            MediaError::TooManyFrames { max } =>
+                write!(f, "animation has more than {} frames", max),
+            MediaError::UnsupportedCodec(codec) => write!(f, "video codec {} is not supported", codec),
+            MediaError::TooLong { duration, max } =>
+                write!(f, "video is {:.1}s long, at most {}s are allowed", duration.as_secs_f64(), max.as_secs()),
+            MediaError::Corrupt(e) => write!(f, "image could not be decoded: {}", e),
+            MediaError::InvalidVideo(e) => write!(f, "video could not be read: {}", e),
+            MediaError::Io(e) => write!(f, "failed to read the upload: {}", e),
+            MediaError::Encode(e) => write!(f, "failed to encode image: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MediaError {}
+
+/// A decoded upload that passed `inspect`.
+pub struct Inspected {
+    pub format: UploadFormat,
+    /// The first frame of animations.
+    pub image: DynamicImage,
+    pub metadata: Metadata,
+    pub animated: bool
+}
+
+/// An upload ready to be stored: the original, stripped of its metadata, and its resized variants.
+pub struct Processed {
+    pub info: ImageInfo,
+    pub original: Vec<u8>,
+    pub variants: Vec<RenderedVariant>,
+    pub placeholder: Placeholder
+}
+
+/// One uploaded file, processed according to what it claims to be.
+pub enum Upload {
+    Image(Processed),
+    Video(ProcessedVideo)
+}
+
+impl Upload {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Upload::Image(image) => image.info.content_type(),
+            Upload::Video(video) => video.info.content_type()
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Upload::Image(image) => image.info.extension(),
+            Upload::Video(video) => video.info.extension()
+        }
+    }
+
+    /// Resized copies of an image, or of the poster of a video.
+    pub fn variants(&self) -> &[RenderedVariant] {
+        match self {
+            Upload::Image(image) => &image.variants,
+            Upload::Video(video) => &video.poster
+        }
+    }
+
+    /// Shown while the image, or the poster of a video, loads.
+    pub fn placeholder(&self) -> &Placeholder {
+        match self {
+            Upload::Image(image) => &image.placeholder,
+            Upload::Video(video) => &video.placeholder
+        }
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Upload::Image(image) => (image.info.width, image.info.height),
+            Upload::Video(video) => (video.info.width, video.info.height)
+        }
+    }
+}
+
+/// Route the upload spooled at `path` to the image or the video pipeline.
+/// Blocking and CPU bound, run it on a blocking thread.
+pub fn process_upload(
+    path: &Path,
+    size: u64,
+    declared_type: &str,
+    settings: &MediaSettings
+) -> Result<Upload, MediaError> {
+    match UploadFormat::from_mime(declared_type) {
+        Some(format) if format.is_video() => video::process(path, size, declared_type, settings).map(Upload::Video),
+        _ => {
+            // Images are decoded in memory, but not before their size is checked
+            if size > settings.max_bytes as u64 {
+                return Err(MediaError::TooLarge { size: size as usize, max: settings.max_bytes })
+            }
+            let data = std::fs::read(path).map_err(MediaError::Io)?;
+            process(&data, declared_type, settings).map(Upload::Image)
+        }
+    }
+}
+
+/// Everything that happens to an image before it is stored. CPU bound, run it on a blocking thread.
+pub fn process(data: &[u8], declared_type: &str, settings: &MediaSettings) -> Result<Processed, MediaError> {
+    let Inspected { format, mut image, metadata, animated } = inspect(data, declared_type, settings)?;
+
+    // Rotating an animation means re-encoding every frame, those are kept the way they came
+    let orientation = if animated { Orientation::NoTransforms } else { metadata.orientation };
+    // Bake the rotation into the pixels, the Exif tag describing it is about to go
+    image.apply_orientation(orientation);
+
+    let reencode_as = |target: ImageFormat| {
+        reencode(&image, target, &metadata, &settings.keep_exif, settings.reencode_quality)
+            .map(|original| (target, original))
+    };
+    let (stored_format, original) = match format {
+        UploadFormat::Png => reencode_as(ImageFormat::Png)?,
+        UploadFormat::Jpeg => reencode_as(ImageFormat::Jpeg)?,
+        UploadFormat::Gif => (ImageFormat::Gif, container::strip_gif(data)?),
+        UploadFormat::Webp if orientation == Orientation::NoTransforms =>
+            (ImageFormat::WebP, container::strip_webp(data)?),
+        UploadFormat::Webp => reencode_as(ImageFormat::WebP)?,
+        // Not every browser can show these, serve something they all can
+        UploadFormat::Heic if image.color().has_alpha() => reencode_as(ImageFormat::Png)?,
+        UploadFormat::Heic => reencode_as(ImageFormat::Jpeg)?,
+        // `inspect` turns these down already
+        UploadFormat::Mp4 | UploadFormat::Webm => return Err(MediaError::UnsupportedFormat(format.mime().to_string())),
+    };
+
+    let variants = render(&image, &settings.variant_sizes, settings.variant_quality)?;
+    let placeholder = placeholder(&image)?;
+
+    Ok(Processed {
+        info: ImageInfo {
+            format: stored_format,
+            width: image.width(),
+            height: image.height()
+        },
+        original,
+        variants,
+        placeholder
+    })
+}
+
+/// Decode a file as it was stored, after it passed `inspect` on its way in.
+pub fn decode_stored(data: &[u8]) -> Result<DynamicImage, MediaError> {
+    ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(MediaError::Io)?
+        .decode()
+        .map_err(|e| MediaError::Corrupt(e.to_string()))
+}
+
+/// Check an upload against `settings` and make sure it really is the image it claims to be.
+///
+/// The declared MIME type comes from the client, so the format is sniffed from the magic
+/// bytes instead and both have to agree. Header dimensions are checked before anything is
+/// decoded, then the whole image, every frame of animations, is decoded under allocation limits.
+pub fn inspect(
+    data: &[u8],
+    declared_type: &str,
+    settings: &MediaSettings
+) -> Result<Inspected, MediaError> {
+    if data.len() > settings.max_bytes {
+        return Err(MediaError::TooLarge { size: data.len(), max: settings.max_bytes })
+    }
+
+    let actual = check_declared(data, declared_type, settings)?;
+
+    match (actual, actual.image_format()) {
+        (_, Some(format)) => decode(data, actual, format, settings),
+        (UploadFormat::Heic, None) => formats::decode_heic(data, settings),
+        _ => Err(MediaError::UnsupportedFormat(declared_type.to_string()))
+    }
+}
+
+/// The declared MIME type has to be accepted and agree with the sniffed format.
+fn check_declared(data: &[u8], declared_type: &str, settings: &MediaSettings) -> Result<UploadFormat, MediaError> {
+    let declared = UploadFormat::from_mime(declared_type)
+        .filter(|f| settings.accepted_formats.contains(f))
+        .ok_or_else(|| MediaError::UnsupportedFormat(declared_type.to_string()))?;
+
+    let actual = sniff(data).ok_or_else(|| {
+        let reason = "unrecognized file signature".to_string();
+        if declared.is_video() { MediaError::InvalidVideo(reason) } else { MediaError::Corrupt(reason) }
+    })?;
+    if actual != declared {
+        return Err(MediaError::FormatMismatch {
+            declared: declared_type.to_string(),
+            actual: actual.mime()
+        })
+    }
+
+    Ok(actual)
+}
+
+fn decode(
+    data: &[u8],
+    upload_format: UploadFormat,
+    format: ImageFormat,
+    settings: &MediaSettings
+) -> Result<Inspected, MediaError> {
+    let mut header = reader(data, format)
+        .into_decoder()
+        .map_err(|e| decode_error(e, upload_format))?;
+    let (width, height) = header.dimensions();
+    let decoded_size = header.total_bytes();
+    check_size(data, width, height, decoded_size, settings)?;
+
+    let metadata = Metadata::read(&mut header);
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(settings.max_width);
+    limits.max_image_height = Some(settings.max_height);
+    limits.max_alloc = Some(decoded_size);
+
+    let mut reader = reader(data, format);
+    reader.limits(limits);
+    let image = reader.decode().map_err(|e| match e {
+        ImageError::Limits(_) => MediaError::DimensionsTooLarge { width, height },
+        e => decode_error(e, upload_format)
+    })?;
+
+    let animated = match upload_format {
+        UploadFormat::Gif | UploadFormat::Webp => check_frames(data, upload_format, width, height, settings)?,
+        _ => false
+    };
+
+    Ok(Inspected {
+        format: upload_format,
+        image,
+        metadata,
+        animated
+    })
+}
+
+/// Header checks shared by all decoders, run before any pixel is decoded.
+fn check_size(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    decoded_size: u64,
+    settings: &MediaSettings
+) -> Result<(), MediaError> {
+    if width > settings.max_width || height > settings.max_height {
+        return Err(MediaError::DimensionsTooLarge { width, height })
+    }
+    check_ratio(data, width, height, decoded_size, settings)
+}
+
+fn check_ratio(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    decoded_size: u64,
+    settings: &MediaSettings
+) -> Result<(), MediaError> {
+    if (width as u64 * height as u64) < settings.decompression_ratio_min_pixels {
+        return Ok(())
+    }
+    let ratio = decoded_size / (data.len() as u64).max(1);
+    if ratio > settings.max_decompression_ratio {
+        return Err(MediaError::DecompressionBomb { ratio })
+    }
+    Ok(())
+}
+
+/// Decode every frame of a GIF or WebP, one at a time, and tell whether it is animated.
+///
+/// Frames add up against the decompression ratio and are bounded by `max_frames`, so a
+/// tiny file can't expand into thousands of full-size frames.
+fn check_frames(
+    data: &[u8],
+    format: UploadFormat,
+    width: u32,
+    height: u32,
+    settings: &MediaSettings
+) -> Result<bool, MediaError> {
+    let frames = match format {
+        UploadFormat::Gif => GifDecoder::new(Cursor::new(data))
+            .map_err(|e| decode_error(e, format))?
+            .into_frames(),
+        UploadFormat::Webp => {
+            let decoder = WebPDecoder::new(Cursor::new(data)).map_err(|e| decode_error(e, format))?;
+            if !decoder.has_animation() {
+                return Ok(false)
+            }
+            decoder.into_frames()
+        }
+        _ => return Ok(false)
+    };
+
+    let frame_size = width as u64 * height as u64 * 4;
+    let mut count = 0;
+    for frame in frames {
+        frame.map_err(|e| decode_error(e, format))?;
+        count += 1;
+        if count > settings.max_frames {
+            return Err(MediaError::TooManyFrames { max: settings.max_frames })
+        }
+        check_ratio(data, width, height, count as u64 * frame_size, settings)?;
+    }
+
+    Ok(count > 1)
+}
+
+fn decode_error(e: ImageError, format: UploadFormat) -> MediaError {
+    match e {
+        // Known format, but this build has no decoder for it
+        ImageError::Unsupported(_) => MediaError::UnsupportedFormat(format.mime().to_string()),
+        e => MediaError::Corrupt(e.to_string())
+    }
+}
+
+fn reader(data: &[u8], format: ImageFormat) -> ImageReader<Cursor<&[u8]>> {
+    ImageReader::with_format(Cursor::new(data), format)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use image::{Rgba, RgbaImage};
+    use super::*;
+
+    /// `configuration/base.yaml`, give or take the variants.
+    pub(crate) fn settings() -> MediaSettings {
+        MediaSettings {
+            max_bytes: 10 << 20,
+            max_width: 8192,
+            max_height: 8192,
+            max_decompression_ratio: 1024,
+            decompression_ratio_min_pixels: 1 << 20,
+            variant_sizes: vec![150],
+            variant_quality: 80,
+            reencode_quality: 92,
+            keep_exif: vec![],
+            accepted_formats: vec![
+                UploadFormat::Png, UploadFormat::Jpeg, UploadFormat::Gif, UploadFormat::Webp, UploadFormat::Heic
+            ],
+            max_frames: 500,
+            max_video_bytes: 100 << 20,
+            max_video_duration: 60,
+            max_request_bytes: 200 << 20
+        }
+    }
+
+    pub(crate) fn png(image: &DynamicImage) -> Vec<u8> {
+        let mut data = Vec::new();
+        image.write_to(&mut Cursor::new(&mut data), ImageFormat::Png).unwrap();
+        data
+    }
+
+    fn flat(width: u32, height: u32) -> Vec<u8> {
+        png(&DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, Rgba([10, 20, 30, 255]))))
+    }
+
+    #[test]
+    fn images_decoding_far_beyond_their_size_are_refused() {
+        let mut settings = settings();
+        settings.max_decompression_ratio = 100;
+        let data = flat(1200, 1000);
+
+        match inspect(&data, "image/png", &settings) {
+            Err(MediaError::DecompressionBomb { ratio }) => assert!(ratio > 100),
+            other => panic!("expected a decompression bomb, got {:?}", other.map(|i| i.format))
+        }
+        assert_eq!(MediaError::DecompressionBomb { ratio: 101 }.code(), "decompression_bomb");
+    }
+
+    #[test]
+    fn small_images_skip_the_ratio_check() {
+        let mut settings = settings();
+        settings.max_decompression_ratio = 100;
+        assert!(inspect(&flat(500, 500), "image/png", &settings).is_ok());
+
+        settings.decompression_ratio_min_pixels = 2_000_000;
+        assert!(inspect(&flat(1200, 1000), "image/png", &settings).is_ok());
+    }
+
+    #[test]
+    fn dimensions_are_checked_before_decoding() {
+        let mut settings = settings();
+        settings.max_width = 100;
+        assert!(matches!(
+            inspect(&flat(101, 10), "image/png", &settings),
+            Err(MediaError::DimensionsTooLarge { width: 101, height: 10 })
+        ));
+    }
+}
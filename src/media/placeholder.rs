@@ -0,0 +1,105 @@
+use image::{DynamicImage, RgbaImage};
+
+use super::MediaError;
+
+
+/// Longest edge of the copy both are computed from, they only depend on broad features.
+const SAMPLE_SIZE: u32 = 64;
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+
+/// What clients show while an image loads.
+#[derive(Debug, Clone)]
+pub struct Placeholder {
+    pub blurhash: String,
+    /// As `#rrggbb`.
+    pub dominant_color: String
+}
+
+/// Compute the placeholder of `image`. CPU bound, run it on a blocking thread.
+pub fn placeholder(image: &DynamicImage) -> Result<Placeholder, MediaError> {
+    let sample = image.thumbnail(SAMPLE_SIZE, SAMPLE_SIZE).to_rgba8();
+    let (x, y) = BLURHASH_COMPONENTS;
+    let blurhash = blurhash::encode(x, y, sample.width(), sample.height(), sample.as_raw())
+        .map_err(|e| MediaError::Encode(format!("blurhash: {:?}", e)))?;
+
+    Ok(Placeholder {
+        blurhash,
+        dominant_color: dominant_color(&sample)
+    })
+}
+
+/// Average of the most common color once quantized to 4 bits per channel, mostly
+/// transparent pixels left out.
+fn dominant_color(image: &RgbaImage) -> String {
+    let mut buckets = vec![(0u32, [0u32; 3]); 1 << 12];
+    for pixel in image.pixels().filter(|pixel| pixel[3] >= 128) {
+        let [r, g, b, _] = pixel.0;
+        let bucket = &mut buckets[(r as usize >> 4) << 8 | (g as usize >> 4) << 4 | b as usize >> 4];
+        bucket.0 += 1;
+        for (sum, channel) in bucket.1.iter_mut().zip([r, g, b]) {
+            *sum += channel as u32;
+        }
+    }
+
+    // The first of equally common colors, so the result doesn't depend on iteration order
+    let (count, sums) = buckets.into_iter()
+        .rev()
+        .max_by_key(|(count, _)| *count)
+        .unwrap_or_default();
+    if count == 0 {
+        // Fully transparent
+        return "#000000".into()
+    }
+    let [r, g, b] = sums.map(|sum| sum / count);
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+    use super::*;
+
+    #[test]
+    fn solid_images_give_their_color() {
+        let image = RgbaImage::from_pixel(8, 8, Rgba([200, 30, 100, 255]));
+        assert_eq!(dominant_color(&image), "#c81e64");
+    }
+
+    #[test]
+    fn transparent_images_give_black() {
+        let image = RgbaImage::from_pixel(8, 8, Rgba([200, 30, 100, 0]));
+        assert_eq!(dominant_color(&image), "#000000");
+    }
+
+    #[test]
+    fn the_most_common_color_wins_without_transparent_pixels() {
+        let mut image = RgbaImage::from_pixel(10, 10, Rgba([255, 255, 255, 20]));
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            if y < 4 {
+                // Both shades fall in the same bucket and are averaged
+                *pixel = Rgba([if x % 2 == 0 { 240 } else { 250 }, 0, 0, 200]);
+            } else if y < 6 {
+                *pixel = Rgba([0, 0, 250, 255]);
+            }
+        }
+        assert_eq!(dominant_color(&image), "#f50000");
+    }
+
+    #[test]
+    fn ties_go_to_the_lowest_bucket() {
+        let mut image = RgbaImage::from_pixel(2, 1, Rgba([0, 200, 0, 255]));
+        image.put_pixel(1, 0, Rgba([200, 0, 0, 255]));
+        assert_eq!(dominant_color(&image), "#00c800");
+        image.put_pixel(0, 0, Rgba([200, 0, 0, 255]));
+        image.put_pixel(1, 0, Rgba([0, 200, 0, 255]));
+        assert_eq!(dominant_color(&image), "#00c800");
+    }
+
+    #[test]
+    fn placeholders_come_with_a_blurhash() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(100, 50, Rgba([10, 20, 30, 255])));
+        let placeholder = placeholder(&image).unwrap();
+        assert_eq!(placeholder.dominant_color, "#0a141e");
+        assert!(blurhash::decode(&placeholder.blurhash, 4, 3, 1.0).is_ok());
+    }
+}
@@ -0,0 +1,111 @@
+use std::io::Cursor;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use serde::Deserialize;
+
+use super::variants::{encode_jpeg, encode_webp};
+use super::{decode_stored, MediaError};
+
+
+/// How an image is fitted into the requested box.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Scale down to fit inside the box, keeping the aspect ratio.
+    #[default]
+    Contain,
+    /// Scale down to fill the box, then crop what sticks out around the center.
+    Cover
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Webp,
+    Jpeg,
+    Png
+}
+
+impl OutputFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png"
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Webp => "webp",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png"
+        }
+    }
+}
+
+/// A rendition of a stored image. Missing dimensions follow from the aspect ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transform {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    pub format: OutputFormat
+}
+
+impl Transform {
+    /// Names the rendition, the same transform always gives the same bytes.
+    pub fn name(&self) -> String {
+        format!(
+            "{}x{}-{:?}.{}",
+            self.width.unwrap_or(0),
+            self.height.unwrap_or(0),
+            self.fit,
+            self.format.extension()
+        ).to_lowercase()
+    }
+}
+
+/// Decode a stored image and render `transform` of it, never upscaling. Animations come out
+/// as their first frame. CPU bound, run it on a blocking thread.
+pub fn transform(data: &[u8], transform: &Transform, quality: u8) -> Result<Vec<u8>, MediaError> {
+    let image = decode_stored(data)?;
+
+    let (width, height) = (image.width(), image.height());
+    let resized = match (transform.width, transform.height, transform.fit) {
+        (Some(w), Some(h), Fit::Cover) => {
+            // Shrink the box along with its aspect ratio until the image can fill it
+            let scale = (width as f64 / w as f64).min(height as f64 / h as f64).min(1.0);
+            let w = ((w as f64 * scale).round() as u32).max(1);
+            let h = ((h as f64 * scale).round() as u32).max(1);
+            image.resize_to_fill(w, h, FilterType::CatmullRom)
+        }
+        (None, None, _) => image,
+        (w, h, _) => {
+            let w = w.unwrap_or(u32::MAX).min(width);
+            let h = h.unwrap_or(u32::MAX).min(height);
+            if w < width || h < height {
+                image.resize(w, h, FilterType::CatmullRom)
+            } else {
+                image
+            }
+        }
+    };
+
+    encode(&resized, transform.format, quality)
+}
+
+fn encode(image: &DynamicImage, format: OutputFormat, quality: u8) -> Result<Vec<u8>, MediaError> {
+    match format {
+        OutputFormat::Webp => encode_webp(image, quality),
+        OutputFormat::Jpeg => encode_jpeg(image, quality),
+        OutputFormat::Png => {
+            let mut encoded = Vec::new();
+            image.write_to(&mut Cursor::new(&mut encoded), ImageFormat::Png)
+                .map_err(|e| MediaError::Encode(format!("png: {}", e)))?;
+            Ok(encoded)
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+use super::MediaError;
+
+
+/// One resized copy of an upload, encoded in every format we serve.
+pub struct RenderedVariant {
+    pub size: u32,
+    pub webp: Vec<u8>,
+    pub jpeg: Vec<u8>
+}
+
+/// Resize `image` so its longest edge fits each of `sizes`, never upscaling.
+///
+/// Works from the largest size down so each step resizes the previous, smaller, image.
+pub fn render(image: &DynamicImage, sizes: &[u32], quality: u8) -> Result<Vec<RenderedVariant>, MediaError> {
+    let mut sizes = sizes.to_vec();
+    sizes.sort_unstable_by(|a, b| b.cmp(a));
+    sizes.dedup();
+
+    let mut source = image.clone();
+    let mut rendered = Vec::with_capacity(sizes.len());
+    for size in sizes {
+        if source.width().max(source.height()) > size {
+            source = source.resize(size, size, FilterType::CatmullRom);
+        }
+
+        rendered.push(RenderedVariant {
+            size,
+            webp: encode_webp(&source, quality)?,
+            jpeg: encode_jpeg(&source, quality)?
+        });
+    }
+
+    Ok(rendered)
+}
+
+pub(super) fn encode_webp(image: &DynamicImage, quality: u8) -> Result<Vec<u8>, MediaError> {
+    let rgba = image.to_rgba8();
+    let encoded = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+        .encode_simple(false, quality as f32)
+        .map_err(|e| MediaError::Encode(format!("webp: {:?}", e)))?;
+    Ok(encoded.to_vec())
+}
+
+pub(super) fn encode_jpeg(image: &DynamicImage, quality: u8) -> Result<Vec<u8>, MediaError> {
+    // JPEG has no alpha channel, transparent pixels come out with their stored color
+    let rgb = image.to_rgb8();
+    let mut encoded = Vec::new();
+    JpegEncoder::new_with_quality(&mut encoded, quality)
+        .encode_image(&rgb)
+        .map_err(|e| MediaError::Encode(format!("jpeg: {}", e)))?;
+    Ok(encoded)
+}
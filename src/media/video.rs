@@ -0,0 +1,221 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+use image::{DynamicImage, Rgb, RgbImage};
+use matroska_demuxer::{Frame, MatroskaFile};
+use mp4parse::{CodecType, SampleEntry, TrackType};
+
+use crate::configuration::{MediaSettings, UploadFormat};
+use super::{check_declared, placeholder, render, MediaError, Placeholder, RenderedVariant};
+
+/// Enough for the `ftyp` box or the EBML header the format is sniffed from.
+const SNIFF_BYTES: u64 = 512;
+
+/// Fill of the placeholder poster. Frames can't be decoded without a video codec.
+const POSTER_COLOR: Rgb<u8> = Rgb([0x1f, 0x1f, 0x1f]);
+
+
+/// What the container says about the video track.
+#[derive(Debug, Clone, Copy)]
+pub struct VideoInfo {
+    pub format: UploadFormat,
+    /// Display size, rotation included.
+    pub width: u32,
+    pub height: u32,
+    pub duration: Duration,
+    pub codec: &'static str
+}
+
+impl VideoInfo {
+    pub fn content_type(&self) -> &'static str {
+        self.format.mime()
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self.format {
+            UploadFormat::Webm => "webm",
+            _ => "mp4"
+        }
+    }
+
+    /// As recorded in `blobs.duration_ms`.
+    pub fn duration_ms(&self) -> Result<i32, MediaError> {
+        i32::try_from(self.duration.as_millis())
+            .map_err(|_| invalid("duration out of range"))
+    }
+}
+
+/// A probed video, stored as it was sent, and the variants of its poster.
+pub struct ProcessedVideo {
+    pub info: VideoInfo,
+    pub poster: Vec<RenderedVariant>,
+    pub placeholder: Placeholder
+}
+
+/// Probe the video at `path` and render a placeholder poster with its aspect ratio.
+pub fn process(path: &Path, size: u64, declared_type: &str, settings: &MediaSettings) -> Result<ProcessedVideo, MediaError> {
+    let file = File::open(path).map_err(MediaError::Io)?;
+    let info = inspect(BufReader::new(file), size, declared_type, settings)?;
+
+    let poster = match settings.variant_sizes.iter().max() {
+        Some(&size) => render(&placeholder_poster(&info, size), &settings.variant_sizes, settings.variant_quality)?,
+        None => Vec::new()
+    };
+
+    let placeholder = placeholder(&placeholder_poster(&info, 32))?;
+
+    Ok(ProcessedVideo {
+        info,
+        poster,
+        placeholder
+    })
+}
+
+/// Check a video upload against `settings`, reading only the container, never the frames.
+pub fn inspect<R: Read + Seek>(
+    mut reader: R,
+    size: u64,
+    declared_type: &str,
+    settings: &MediaSettings
+) -> Result<VideoInfo, MediaError> {
+    if size > settings.max_video_bytes as u64 {
+        return Err(MediaError::TooLarge { size: size as usize, max: settings.max_video_bytes })
+    }
+
+    let mut head = Vec::new();
+    (&mut reader).take(SNIFF_BYTES).read_to_end(&mut head).map_err(MediaError::Io)?;
+    reader.seek(SeekFrom::Start(0)).map_err(MediaError::Io)?;
+
+    let max_duration = Duration::from_secs(settings.max_video_duration as u64);
+    let info = match check_declared(&head, declared_type, settings)? {
+        UploadFormat::Mp4 => probe_mp4(&mut reader)?,
+        UploadFormat::Webm => probe_webm(reader, max_duration)?,
+        _ => return Err(MediaError::UnsupportedFormat(declared_type.to_string()))
+    };
+
+    if info.width > settings.max_width || info.height > settings.max_height {
+        return Err(MediaError::DimensionsTooLarge { width: info.width, height: info.height })
+    }
+    if info.duration > max_duration {
+        return Err(MediaError::TooLong { duration: info.duration, max: max_duration })
+    }
+
+    Ok(info)
+}
+
+fn probe_mp4(reader: &mut impl Read) -> Result<VideoInfo, MediaError> {
+    let context = mp4parse::read_mp4(reader)
+        .map_err(|e| MediaError::InvalidVideo(format!("mp4: {}", e)))?;
+
+    let track = context.tracks.iter()
+        .find(|t| t.track_type == TrackType::Video)
+        .ok_or_else(|| invalid("no video track"))?;
+    let entry = match track.stsd.as_ref().and_then(|stsd| stsd.descriptions.first()) {
+        Some(SampleEntry::Video(entry)) => entry,
+        // HEVC among others, the parser only describes what browsers can play
+        Some(_) => return Err(MediaError::UnsupportedCodec("unknown".into())),
+        None => return Err(invalid("video track has no sample description"))
+    };
+
+    let codec = match entry.codec_type {
+        CodecType::H264 => "h264",
+        CodecType::VP9 => "vp9",
+        CodecType::AV1 => "av1",
+        other => return Err(MediaError::UnsupportedCodec(format!("{:?}", other).to_lowercase()))
+    };
+
+    // The track header has the display size in 16.16 fixed point, before its matrix rotates it
+    let (mut width, mut height) = match &track.tkhd {
+        Some(tkhd) if tkhd.width >> 16 > 0 && tkhd.height >> 16 > 0 => (tkhd.width >> 16, tkhd.height >> 16),
+        _ => (entry.width as u32, entry.height as u32)
+    };
+    if track.tkhd.as_ref().is_some_and(|tkhd| tkhd.matrix.a == 0 && tkhd.matrix.d == 0) {
+        std::mem::swap(&mut width, &mut height);
+    }
+
+    let duration = match (track.duration, track.timescale) {
+        (Some(duration), Some(timescale)) if duration.0 > 0 && timescale.0 > 0 =>
+            seconds(duration.0 as f64 / timescale.0 as f64)?,
+        // Fragmented files leave the tracks empty and give the total in `mvex`
+        _ => match (context.mvex.and_then(|mvex| mvex.fragment_duration), context.timescale) {
+            (Some(duration), Some(timescale)) if timescale.0 > 0 =>
+                seconds(duration.0 as f64 / timescale.0 as f64)?,
+            _ => return Err(invalid("video has no duration"))
+        }
+    };
+
+    Ok(VideoInfo {
+        format: UploadFormat::Mp4,
+        width,
+        height,
+        duration,
+        codec
+    })
+}
+
+/// Reading frames for the duration stops once `max_duration` is exceeded, the video is turned
+/// down either way.
+fn probe_webm(reader: impl Read + Seek, max_duration: Duration) -> Result<VideoInfo, MediaError> {
+    let corrupt = |e: matroska_demuxer::DemuxError| MediaError::InvalidVideo(format!("webm: {}", e));
+
+    let mut file = MatroskaFile::open(reader).map_err(corrupt)?;
+
+    let track = file.tracks().iter()
+        .find(|t| t.track_type() == matroska_demuxer::TrackType::Video)
+        .ok_or_else(|| invalid("no video track"))?;
+    let codec = match track.codec_id() {
+        "V_VP8" => "vp8",
+        "V_VP9" => "vp9",
+        "V_AV1" => "av1",
+        other => return Err(MediaError::UnsupportedCodec(other.to_string()))
+    };
+    let video = track.video()
+        .ok_or_else(|| invalid("video track has no dimensions"))?;
+    let width = video.display_width().unwrap_or(video.pixel_width()).get() as u32;
+    let height = video.display_height().unwrap_or(video.pixel_height()).get() as u32;
+
+    // Timestamps count in units of `timestamp_scale` nanoseconds
+    let scale = file.info().timestamp_scale().get() as f64;
+    let ticks = match file.info().duration() {
+        Some(ticks) => ticks,
+        // Live recordings are written before their length is known, the last frame tells it
+        None => {
+            let max_ticks = max_duration.as_secs_f64() * 1e9 / scale;
+            let mut frame = Frame::default();
+            let mut last = 0;
+            while file.next_frame(&mut frame).map_err(corrupt)? {
+                last = last.max(frame.timestamp);
+                if last as f64 > max_ticks {
+                    break
+                }
+            }
+            last as f64
+        }
+    };
+
+    Ok(VideoInfo {
+        format: UploadFormat::Webm,
+        width,
+        height,
+        duration: seconds(ticks * scale / 1e9)?,
+        codec
+    })
+}
+
+fn seconds(secs: f64) -> Result<Duration, MediaError> {
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| MediaError::InvalidVideo(format!("invalid duration {}", secs)))
+}
+
+fn invalid(reason: &str) -> MediaError {
+    MediaError::InvalidVideo(reason.to_string())
+}
+
+/// A flat frame with the video's aspect ratio, its longest edge `size` pixels long.
+fn placeholder_poster(info: &VideoInfo, size: u32) -> DynamicImage {
+    let longest = info.width.max(info.height).max(1) as u64;
+    let scale = |edge: u32| ((edge as u64 * size as u64 / longest) as u32).max(1);
+    DynamicImage::ImageRgb8(RgbImage::from_pixel(scale(info.width), scale(info.height), POSTER_COLOR))
+}
@@ -0,0 +1,130 @@
+use chrono::{DateTime, NaiveDateTime};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use uuid::Uuid;
+
+use super::{Comment, PostRow};
+
+pub const DEFAULT_PAGE_SIZE: i64 = 10;
+pub const MAX_PAGE_SIZE: i64 = 50;
+
+
+/// Position in a `(created_at, id)` ordered list of posts or comments.
+///
+/// Travels to clients as an opaque url-safe string, so the encoding can change
+/// without breaking them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub created_at: NaiveDateTime,
+    pub id: Uuid
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        let raw = format!("{}|{}", self.created_at.and_utc().timestamp_micros(), self.id);
+        base64::encode_config(raw, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn decode(encoded: &str) -> Option<Self> {
+        let raw = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).ok()?;
+        let raw = String::from_utf8(raw).ok()?;
+        let (micros, id) = raw.split_once('|')?;
+        let created_at = DateTime::from_timestamp_micros(micros.parse().ok()?)?.naive_utc();
+        Some(Cursor {
+            created_at,
+            id: id.parse().ok()?
+        })
+    }
+}
+
+impl Serialize for Cursor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.encode())
+    }
+}
+
+impl<'de> Deserialize<'de> for Cursor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        Cursor::decode(&encoded).ok_or_else(|| de::Error::custom("invalid cursor"))
+    }
+}
+
+/// Clamp a client supplied `limit` to something sane.
+pub fn page_size(limit: Option<i64>) -> i64 {
+    limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+}
+
+/// Rows a `Cursor` can point at.
+pub trait Keyed {
+    fn cursor(&self) -> Cursor;
+}
+
+impl Keyed for PostRow {
+    fn cursor(&self) -> Cursor {
+        Cursor { created_at: self.created_at, id: self.id }
+    }
+}
+
+impl Keyed for Comment {
+    fn cursor(&self) -> Cursor {
+        Cursor { created_at: self.created_at, id: self.id }
+    }
+}
+
+/// Queries fetch `limit + 1` rows; the extra row only tells us there is a next page.
+pub fn split_page<T: Keyed>(mut rows: Vec<T>, limit: i64) -> (Vec<T>, Option<Cursor>) {
+    if rows.len() as i64 <= limit {
+        return (rows, None)
+    }
+    rows.truncate(limit as usize);
+    let next_cursor = rows.last().map(Keyed::cursor);
+    (rows, next_cursor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cursor() -> Cursor {
+        Cursor {
+            created_at: DateTime::from_timestamp_micros(1_660_000_000_123_456).unwrap().naive_utc(),
+            id: Uuid::new_v4()
+        }
+    }
+
+    #[test]
+    fn encode_decode_round_trips() {
+        let cursor = cursor();
+        assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+    }
+
+    #[test]
+    fn encoding_is_url_safe() {
+        let encoded = cursor().encode();
+        assert!(encoded.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    }
+
+    #[test]
+    fn serde_round_trips() {
+        let cursor = cursor();
+        let json = serde_json::to_string(&cursor).unwrap();
+        assert_eq!(json, format!("\"{}\"", cursor.encode()));
+        assert_eq!(serde_json::from_str::<Cursor>(&json).unwrap(), cursor);
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        assert_eq!(Cursor::decode(""), None);
+        assert_eq!(Cursor::decode("not a cursor!"), None);
+        assert_eq!(Cursor::decode(&base64::encode_config("123", base64::URL_SAFE_NO_PAD)), None);
+        assert_eq!(Cursor::decode(&base64::encode_config("abc|def", base64::URL_SAFE_NO_PAD)), None);
+        assert!(serde_json::from_str::<Cursor>("\"nope\"").is_err());
+    }
+
+    #[test]
+    fn page_size_is_clamped() {
+        assert_eq!(page_size(None), DEFAULT_PAGE_SIZE);
+        assert_eq!(page_size(Some(0)), 1);
+        assert_eq!(page_size(Some(MAX_PAGE_SIZE + 1)), MAX_PAGE_SIZE);
+    }
+}
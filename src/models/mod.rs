@@ -1,28 +1,241 @@
-use actix_extract_multipart::File;
+use std::collections::BTreeMap;
 use uuid::Uuid;
 use chrono::NaiveDateTime;
 use serde::{Serialize, Deserialize};
+use validator::{Validate, ValidationError};
+use sqlx::types::Json;
+use crate::error::ApiError;
+use crate::extract::{FormData, FormFile, FromForm};
+use crate::configuration::FileUrlSettings;
+use crate::media::{Fit, OutputFormat};
+use crate::signing::{url_expiry, Signer};
+use crate::storage::{Storage, StorageError};
 
+mod cursor;
 
-#[derive(Debug, Serialize, sqlx::FromRow)]
+pub use cursor::{page_size, split_page, Cursor, MAX_PAGE_SIZE};
+
+/// Images a single post can carry.
+pub const MAX_POST_MEDIA: usize = 10;
+/// Bounds of the usernames a request may name, in characters.
+pub const MIN_USERNAME_LENGTH: usize = 3;
+pub const MAX_USERNAME_LENGTH: usize = 20;
+
+
+#[derive(Debug, Serialize)]
 pub struct Post {
     pub id: Uuid,
     pub username: String,
-    pub img_url: String,
     pub caption: Option<String>,
     pub likes: i32,
-    pub created_at: NaiveDateTime
+    pub created_at: NaiveDateTime,
+    /// Only validates cached copies, likes count as changes.
+    #[serde(skip)]
+    pub updated_at: NaiveDateTime,
+    /// BlurHash of the first image, unknown until computed for older posts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+    /// Of the first image, as `#rrggbb`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dominant_color: Option<String>,
+    pub media: Vec<Media>
 }
 
-#[derive(Debug, Deserialize, Validate)]
-pub struct PostCreate {
-    #[validate(length(min = 3, max = 20))]
+impl Post {
+    pub fn new(row: PostRow, media: Vec<Media>) -> Self {
+        Post {
+            id: row.id,
+            username: row.username,
+            caption: row.caption,
+            likes: row.likes,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            blurhash: row.blurhash,
+            dominant_color: row.dominant_color,
+            media
+        }
+    }
+
+    /// Media are persisted as storage keys, swap them for the URLs clients fetch them from.
+    pub async fn with_public_url(mut self, storage: &dyn Storage, file_urls: &FileUrlSettings) -> Result<Self, StorageError> {
+        let signer = Signer::new(file_urls.secret.clone());
+        let expires = url_expiry(file_urls.expiry_seconds);
+        for media in &mut self.media {
+            let payload = image_payload(media.id);
+            media.resize_url = Some(format!(
+                "/img/{}?expires={}&signature={}",
+                media.id, expires, signer.signature(&payload, expires)
+            ));
+            media.img_url = storage.url(&media.img_url).await?;
+            for files in media.variants.values_mut() {
+                files.webp = storage.url(&files.webp).await?;
+                files.jpeg = storage.url(&files.jpeg).await?;
+            }
+        }
+        Ok(self)
+    }
+}
+
+/// A `posts` row, `Post` without its media.
+#[derive(Debug, sqlx::FromRow)]
+pub struct PostRow {
+    pub id: Uuid,
     pub username: String,
-    pub img_file: File,
+    pub caption: Option<String>,
+    pub likes: i32,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub blurhash: Option<String>,
+    pub dominant_color: Option<String>
+}
+
+/// One image or video of a post, in display order.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Media {
+    #[serde(skip)]
+    pub post_id: Uuid,
+    /// The stored file, shared by every post carrying the same content.
+    #[serde(skip)]
+    pub id: Uuid,
+    pub kind: MediaKind,
+    /// The image, or the video file itself.
+    pub img_url: String,
+    pub alt_text: Option<String>,
+    /// For videos, resized copies of the poster.
+    pub variants: Json<Variants>,
+    /// Unknown for images posted before they were recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codec: Option<String>,
+    /// The earliest post that already carried the same file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repost_of: Option<Uuid>,
+    /// Signed `/img/{id}` URL of the image, or the poster of videos. Takes `w`, `h`, `fit`
+    /// and `format` on top, see `ImageQuery`. Set by `Post::with_public_url`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resize_url: Option<String>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum MediaKind {
+    Image,
+    Video
+}
+
+/// Resized copies of a post image, keyed by the length of their longest edge.
+pub type Variants = BTreeMap<u32, VariantFiles>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VariantFiles {
+    pub webp: String,
+    pub jpeg: String
+}
+
+/// Sent as `multipart/form-data`: one `img_file` part per image and optionally as many
+/// `alt_text` parts, matched to the images by position.
+#[derive(Debug, Validate)]
+pub struct PostCreate {
+    // Keep in line with `MAX_POST_MEDIA`
+    #[validate(length(min = 1, max = 10))]
+    #[validate]
+    pub media: Vec<MediaCreate>,
+    #[validate(length(max = 256))]
+    pub caption: Option<String>
+}
+
+/// Serialized only for the validation error details, which leave the file out.
+#[derive(Debug, Serialize, Validate)]
+pub struct MediaCreate {
+    #[serde(skip)]
+    pub img_file: FormFile,
+    #[validate(length(max = 1000))]
+    pub alt_text: Option<String>
+}
+
+impl FromForm for PostCreate {
+    const MAX_FILES: usize = MAX_POST_MEDIA;
+
+    fn from_form(mut form: FormData) -> Result<Self, ApiError> {
+        let files = form.take_files("img_file");
+        let alt_texts = form.take_texts("alt_text");
+        if alt_texts.len() > files.len() {
+            return Err(ApiError::BadRequest("there are more alt_text fields than img_file fields".into()))
+        }
+
+        let mut alt_texts = alt_texts.into_iter();
+        let media = files.into_iter()
+            .map(|img_file| MediaCreate {
+                img_file,
+                // Empty parts keep the position of an image without alt text
+                alt_text: alt_texts.next().filter(|alt| !alt.is_empty())
+            })
+            .collect();
+
+        Ok(PostCreate {
+            media,
+            caption: form.take_text("caption")
+        })
+    }
+}
+
+/// A resumable upload in progress, offsets count bytes received.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct UploadSession {
+    pub id: Uuid,
+    pub file_name: String,
+    pub content_type: String,
+    pub upload_length: i64,
+    pub upload_offset: i64,
+    pub expires_at: NaiveDateTime
+}
+
+/// Turns resumable or direct uploads into a post, like `PostCreate` does for form uploads.
+#[derive(Debug, Deserialize, Validate)]
+pub struct PostFromUploads {
+    // Keep in line with `MAX_POST_MEDIA`
+    #[validate(length(min = 1, max = 10))]
+    #[validate]
+    pub media: Vec<UploadedMedia>,
     #[validate(length(max = 256))]
     pub caption: Option<String>
 }
 
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct UploadedMedia {
+    pub upload_id: Uuid,
+    #[validate(length(max = 1000))]
+    pub alt_text: Option<String>
+}
+
+/// Announces an upload sent straight to storage, which is checked against it once it is there.
+#[derive(Debug, Deserialize, Validate)]
+pub struct DirectUploadCreate {
+    pub content_type: String,
+    #[validate(range(min = 1))]
+    pub size: u64,
+    /// Hex encoded SHA-256 of the content.
+    #[validate(length(equal = 64))]
+    pub sha256: Option<String>
+}
+
+/// Where and how to send the bytes of a direct upload.
+#[derive(Debug, Serialize)]
+pub struct DirectUpload {
+    pub upload_id: Uuid,
+    pub method: &'static str,
+    pub url: String,
+    /// Headers the request has to carry.
+    pub headers: BTreeMap<&'static str, String>,
+    pub expires_at: NaiveDateTime
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PostID {
     pub id: Uuid
@@ -31,21 +244,149 @@ pub struct PostID {
 #[derive(Debug, Deserialize, Validate)]
 pub struct PostUpdate {
     pub id: Uuid,
+    #[validate(length(max = 256))]
     pub caption: Option<String>
 }
 
 #[derive(Debug, Deserialize, Validate)]
 pub struct FeedFollowing {
-    pub page: i32,
-    pub followings: Vec<String>
+    #[validate(length(max = 1000), custom = "validate_usernames")]
+    pub followings: Vec<String>,
+    #[validate(range(min = 1, max = "MAX_PAGE_SIZE"))]
+    pub limit: Option<i64>,
+    pub cursor: Option<Cursor>
+}
+
+fn validate_usernames(usernames: &[String]) -> Result<(), ValidationError> {
+    let valid = |username: &String| (MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&username.chars().count());
+    if !usernames.iter().all(valid) {
+        let mut error = ValidationError::new("length");
+        error.add_param("min".into(), &MIN_USERNAME_LENGTH);
+        error.add_param("max".into(), &MAX_USERNAME_LENGTH);
+        return Err(error)
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct PostsPage {
+    #[validate(range(min = 1, max = "MAX_PAGE_SIZE"))]
+    pub limit: Option<i64>,
+    pub cursor: Option<Cursor>
 }
 
 #[derive(Debug, Serialize)]
 pub struct LatestPosts {
-    pub posts: Vec<Post>
+    pub posts: Vec<Post>,
+    pub next_cursor: Option<Cursor>
 }
 
 #[derive(Debug, Serialize)]
 pub struct UserPosts {
-    pub posts: Vec<Post>
-}
\ No newline at end of file
+    pub posts: Vec<Post>,
+    pub next_cursor: Option<Cursor>
+}
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Like {
+    pub username: String,
+    pub created_at: NaiveDateTime
+}
+
+#[derive(Debug, Serialize)]
+pub struct LikeCount {
+    pub likes: i32
+}
+
+#[derive(Debug, Serialize)]
+pub struct PostLikes {
+    pub likes: Vec<Like>
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Comment {
+    pub id: Uuid,
+    pub post_id: Uuid,
+    pub username: String,
+    pub body: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: Option<NaiveDateTime>
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CommentCreate {
+    #[validate(length(min = 1, max = 256))]
+    pub body: String
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CommentUpdate {
+    #[validate(length(min = 1, max = 256))]
+    pub body: String
+}
+
+/// What `/img/{id}` URLs sign, kept apart from the storage keys `/files` URLs sign with
+/// the same secret.
+pub fn image_payload(id: Uuid) -> String {
+    format!("img/{}", id)
+}
+
+/// Query of `/img/{id}`, sizes are limited to `ImageSettings::sizes`. `expires` and
+/// `signature` come from `Media::resize_url`, both missing when the URL wasn't signed.
+#[derive(Debug, Deserialize)]
+pub struct ImageQuery {
+    /// Unix seconds.
+    pub expires: Option<u64>,
+    pub signature: Option<String>,
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    #[serde(default)]
+    pub fit: Fit,
+    #[serde(default)]
+    pub format: OutputFormat
+}
+
+/// Query of the URLs local files are served under, both missing when the URL wasn't signed.
+#[derive(Debug, Deserialize)]
+pub struct SignedFile {
+    /// Unix seconds.
+    pub expires: Option<u64>,
+    pub signature: Option<String>
+}
+
+/// Comments come oldest first, `cursor` is the last one of the previous page.
+#[derive(Debug, Deserialize, Validate)]
+pub struct CommentsPage {
+    #[validate(range(min = 1, max = "MAX_PAGE_SIZE"))]
+    pub limit: Option<i64>,
+    pub cursor: Option<Cursor>
+}
+
+#[derive(Debug, Serialize)]
+pub struct PostComments {
+    pub comments: Vec<Comment>,
+    pub next_cursor: Option<Cursor>
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn following(usernames: &[&str]) -> FeedFollowing {
+        FeedFollowing {
+            followings: usernames.iter().map(|u| u.to_string()).collect(),
+            limit: None,
+            cursor: None
+        }
+    }
+
+    #[test]
+    fn followed_usernames_are_3_to_20_characters() {
+        assert!(following(&["bob", "alice", &"a".repeat(20)]).validate().is_ok());
+        // Characters, not bytes
+        assert!(following(&[&"é".repeat(20)]).validate().is_ok());
+
+        assert!(following(&["alice", "al"]).validate().is_err());
+        assert!(following(&[""]).validate().is_err());
+        assert!(following(&[&"a".repeat(21)]).validate().is_err());
+    }
+}
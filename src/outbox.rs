@@ -0,0 +1,297 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::configuration::ReaperSettings;
+use crate::storage::{Storage, StorageError, INCOMING_PREFIX};
+
+/// Uploads are written under this prefix and only moved to their real key once the post
+/// referencing them is committed, so a failed insert never leaves a servable file behind.
+/// Each upload stages in a directory of its own, `staging/<upload>/<key>`, as concurrent
+/// uploads of the same content share their keys.
+pub const STAGING_PREFIX: &str = "staging/";
+
+/// How long the request that queued an entry has to carry it out before the reaper may.
+const REQUEST_WINDOW_SECONDS: i32 = 60;
+
+pub fn staged_key(upload: Uuid, key: &str) -> String {
+    format!("{}{}/{}", STAGING_PREFIX, upload, key)
+}
+
+/// Where the object staged at `staged` belongs.
+fn promoted_key(staged: &str) -> Option<&str> {
+    staged.strip_prefix(STAGING_PREFIX)?
+        .split_once('/')
+        .map(|(_, key)| key)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum OutboxAction {
+    /// Move the staged `key` to where it belongs.
+    Promote,
+    /// Remove `<key>`.
+    Delete
+}
+
+/// Record storage work in the transaction that makes it necessary, it is owed once that commits.
+pub async fn enqueue(
+    transaction: &mut Transaction<'_, Postgres>,
+    action: OutboxAction,
+    keys: &[String]
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO storage_outbox (action, key, next_attempt_at)
+        SELECT $1, key, current_timestamp + $3 * interval '1 second'
+        FROM unnest($2::text[]) AS key
+        "#,
+        action as _,
+        keys,
+        REQUEST_WINDOW_SECONDS as f64
+    )
+        .execute(transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
+    Ok(())
+}
+
+/// Carry out freshly committed entries from the request itself. Whatever fails stays in
+/// the outbox for the reaper, so errors are only logged.
+///
+/// Entries are claimed before anything is done about them, and only the keys still queued
+/// are acted on. A post re-uploading deleted content cancels the deletion of its files, and
+/// its row locks wait for this transaction, so those files are never removed from under it.
+#[instrument(
+    name = "Processing storage outbox entries",
+    skip(pool, storage, keys),
+    fields(
+        entries = keys.len()
+    )
+)]
+pub async fn process_now(pool: &PgPool, storage: &dyn Storage, action: OutboxAction, keys: &[String]) {
+    if let Err(e) = claim_and_perform(pool, storage, action, keys).await {
+        // Harmless, the reaper carries out whatever is left
+        tracing::error!("Failed to process storage outbox entries {:?}", e);
+    }
+}
+
+async fn claim_and_perform(
+    pool: &PgPool,
+    storage: &dyn Storage,
+    action: OutboxAction,
+    keys: &[String]
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let claimed = sqlx::query_scalar!(
+        r#"DELETE FROM storage_outbox WHERE action = $1 AND key = ANY($2) RETURNING key"#,
+        action as _,
+        keys
+    )
+        .fetch_all(&mut transaction)
+        .await?;
+
+    let mut failed = Vec::new();
+    for key in claimed.into_iter().collect::<HashSet<_>>() {
+        if let Err(e) = perform(storage, action, &key).await {
+            tracing::warn!("Leaving {:?} of {} to the reaper: {}", action, key, e);
+            failed.push(key);
+        }
+    }
+    if !failed.is_empty() {
+        enqueue(&mut transaction, action, &failed).await?;
+    }
+
+    transaction.commit().await
+}
+
+/// Idempotent, the reaper may retry an entry the request already carried out.
+async fn perform(storage: &dyn Storage, action: OutboxAction, key: &str) -> Result<(), StorageError> {
+    match action {
+        OutboxAction::Promote => {
+            let target = promoted_key(key)
+                .ok_or_else(|| StorageError::Backend(format!("{} is not a staged key", key)))?;
+            match storage.rename(key, target).await {
+                Err(StorageError::NotFound(_)) if exists(storage, target).await? => Ok(()),
+                result => result
+            }
+        }
+        OutboxAction::Delete => match storage.delete(key).await {
+            Err(StorageError::NotFound(_)) => Ok(()),
+            result => result
+        }
+    }
+}
+
+async fn exists(storage: &dyn Storage, key: &str) -> Result<bool, StorageError> {
+    Ok(storage.list(key).await?.iter().any(|object| object.key == key))
+}
+
+/// Retry the outbox and garbage-collect storage every `interval_seconds`, forever.
+pub async fn run_reaper(pool: PgPool, storage: Arc<dyn Storage>, settings: ReaperSettings) {
+    let mut interval = tokio::time::interval(Duration::from_secs(settings.interval_seconds));
+    loop {
+        interval.tick().await;
+        if let Err(e) = drain(&pool, storage.as_ref(), &settings).await {
+            tracing::error!("Failed to drain the storage outbox: {:?}", e);
+        }
+        if let Err(e) = sweep(&pool, storage.as_ref(), &settings).await {
+            tracing::error!("Failed to sweep storage: {:?}", e);
+        }
+    }
+}
+
+/// Retry due outbox entries. Rows are locked while they are worked on, so several
+/// instances can run the reaper side by side.
+#[instrument(name = "Draining the storage outbox", skip_all)]
+async fn drain(pool: &PgPool, storage: &dyn Storage, settings: &ReaperSettings) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let entries = sqlx::query!(
+        r#"
+        SELECT id, action as "action: OutboxAction", key, attempts
+        FROM storage_outbox
+        WHERE next_attempt_at <= current_timestamp AND attempts < $1
+        ORDER BY next_attempt_at
+        LIMIT $2
+        FOR UPDATE SKIP LOCKED
+        "#,
+        settings.max_attempts,
+        settings.batch_size
+    )
+        .fetch_all(&mut transaction)
+        .await?;
+
+    for entry in entries {
+        match perform(storage, entry.action, &entry.key).await {
+            Ok(()) => {
+                sqlx::query!(r#"DELETE FROM storage_outbox WHERE id = $1"#, entry.id)
+                    .execute(&mut transaction)
+                    .await?;
+            }
+            Err(e) => {
+                if entry.attempts + 1 >= settings.max_attempts {
+                    tracing::error!("Giving up on {:?} of {}: {}", entry.action, entry.key, e);
+                } else {
+                    tracing::warn!("Failed to {:?} {}, will retry: {}", entry.action, entry.key, e);
+                }
+                // Back off exponentially, capped at an hour
+                sqlx::query!(
+                    r#"
+                    UPDATE storage_outbox
+                    SET attempts = attempts + 1,
+                        last_error = $2,
+                        next_attempt_at = current_timestamp + least(power(2, attempts), 3600) * interval '1 second'
+                    WHERE id = $1
+                    "#,
+                    entry.id,
+                    e.to_string()
+                )
+                    .execute(&mut transaction)
+                    .await?;
+            }
+        }
+    }
+
+    transaction.commit().await
+}
+
+/// Remove objects older than the grace period that nothing points to: staged uploads that
+/// were never promoted, direct uploads that were never posted, and blobs no post references
+/// anymore.
+#[instrument(name = "Sweeping storage for orphans", skip_all)]
+async fn sweep(pool: &PgPool, storage: &dyn Storage, settings: &ReaperSettings) -> eyre::Result<()> {
+    // Direct uploads are given up on once their URL has been expired for the grace period
+    sqlx::query!(
+        r#"
+        DELETE FROM direct_uploads
+        WHERE expires_at < current_timestamp - $1 * interval '1 second'
+        "#,
+        settings.grace_period_seconds as f64
+    )
+        .execute(pool)
+        .await?;
+
+    let cutoff = SystemTime::now() - Duration::from_secs(settings.grace_period_seconds);
+    let objects: Vec<String> = storage.list("").await?
+        .into_iter()
+        .filter(|object| object.modified < cutoff)
+        .map(|object| object.key)
+        .collect();
+
+    let mut orphans = Vec::new();
+    for batch in objects.chunks(settings.batch_size.max(1) as usize) {
+        let (staged, stored): (Vec<&String>, Vec<&String>) = batch.iter()
+            .partition(|key| key.starts_with(STAGING_PREFIX));
+        let (incoming, stored): (Vec<&String>, Vec<&String>) = stored.into_iter()
+            .partition(|key| key.starts_with(INCOMING_PREFIX));
+
+        // Staged objects are kept while a promotion is still owed
+        let pending: HashSet<String> = sqlx::query_scalar!(
+            r#"SELECT key FROM storage_outbox WHERE action = 'promote' AND key = ANY($1)"#,
+            &staged.iter().map(|key| key.to_string()).collect::<Vec<_>>()[..]
+        )
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .collect();
+        orphans.extend(staged.into_iter()
+            .filter(|key| !pending.contains(*key))
+            .cloned());
+
+        // Incoming objects are kept while their direct upload may still be posted
+        let ids: Vec<Uuid> = incoming.iter()
+            .filter_map(|key| key[INCOMING_PREFIX.len()..].parse().ok())
+            .collect();
+        let announced: HashSet<Uuid> = sqlx::query_scalar!(
+            r#"SELECT id FROM direct_uploads WHERE id = ANY($1)"#,
+            &ids[..]
+        )
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .collect();
+        orphans.extend(incoming.into_iter()
+            .filter(|key| !key[INCOMING_PREFIX.len()..].parse().is_ok_and(|id| announced.contains(&id)))
+            .cloned());
+
+        // Variants live under `<stem>/`, next to their original `<stem>.<ext>`
+        let stems: Vec<String> = stored.iter().map(|key| stem(key).to_string()).collect();
+        let live: HashSet<String> = sqlx::query_scalar!(
+            r#"
+            SELECT split_part(img_url, '.', 1) as "stem!"
+            FROM blobs
+            WHERE split_part(img_url, '.', 1) = ANY($1)
+            "#,
+            &stems[..]
+        )
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .collect();
+        orphans.extend(stored.into_iter()
+            .filter(|key| !live.contains(stem(key)))
+            .cloned());
+    }
+
+    for key in &orphans {
+        match storage.delete(key).await {
+            Ok(()) | Err(StorageError::NotFound(_)) => tracing::info!("Removed orphaned object {}", key),
+            Err(e) => tracing::warn!("Failed to remove orphaned object {}: {}", key, e)
+        }
+    }
+    Ok(())
+}
+
+fn stem(key: &str) -> &str {
+    let end = key.find(['/', '.']).unwrap_or(key.len());
+    &key[..end]
+}
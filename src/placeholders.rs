@@ -0,0 +1,88 @@
+use actix_web::web;
+use sqlx::PgPool;
+use sqlx::types::Json;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::media::{self, Placeholder};
+use crate::models::{MediaKind, Variants};
+use crate::storage::Storage;
+
+/// Posts filled in per query by `backfill`.
+const BATCH_SIZE: i64 = 100;
+
+/// Key of a still image of stored media: the image itself, or the largest poster of a video.
+pub fn still_key<'a>(kind: MediaKind, img_url: &'a str, variants: &'a Variants) -> Option<&'a str> {
+    match kind {
+        MediaKind::Image => Some(img_url),
+        MediaKind::Video => variants.values().next_back().map(|files| files.jpeg.as_str())
+    }
+}
+
+/// Placeholder of media that is already in storage.
+pub async fn of_stored(
+    storage: &dyn Storage,
+    kind: MediaKind,
+    img_url: &str,
+    variants: &Variants
+) -> eyre::Result<Placeholder> {
+    let key = still_key(kind, img_url, variants)
+        .ok_or_else(|| eyre::eyre!("{} has no poster", img_url))?;
+    let data = storage.get(key).await?;
+    let placeholder = web::block(move || media::placeholder(&media::decode_stored(&data)?)).await??;
+    Ok(placeholder)
+}
+
+/// Compute the placeholders of posts from before they were. Posts whose first image can't
+/// be read are logged and left without one. Returns how many posts were filled in.
+#[instrument(name = "Backfilling post placeholders", skip_all)]
+pub async fn backfill(pool: &PgPool, storage: &dyn Storage) -> eyre::Result<u64> {
+    let mut filled = 0;
+    let mut after = Uuid::nil();
+    loop {
+        let covers = sqlx::query!(
+            r#"
+            SELECT posts.id, blobs.kind as "kind: MediaKind", blobs.img_url, blobs.variants as "variants: Json<Variants>"
+            FROM posts
+            JOIN post_media ON post_media.post_id = posts.id AND post_media.position = 0
+            JOIN blobs ON blobs.id = post_media.blob_id
+            WHERE posts.blurhash IS NULL AND posts.id > $1
+            ORDER BY posts.id
+            LIMIT $2
+            "#,
+            after,
+            BATCH_SIZE
+        )
+            .fetch_all(pool)
+            .await?;
+        let Some(last) = covers.last() else {
+            break
+        };
+        after = last.id;
+
+        for cover in covers {
+            let placeholder = match of_stored(storage, cover.kind, &cover.img_url, &cover.variants).await {
+                Ok(placeholder) => placeholder,
+                Err(e) => {
+                    tracing::warn!("Leaving post {} without a placeholder: {:?}", cover.id, e);
+                    continue
+                }
+            };
+            // The post reads differently, cached copies have to be fetched again
+            sqlx::query!(
+                r#"
+                UPDATE posts
+                SET blurhash = $2, dominant_color = $3, updated_at = current_timestamp
+                WHERE id = $1 AND blurhash IS NULL
+                "#,
+                cover.id,
+                placeholder.blurhash,
+                placeholder.dominant_color
+            )
+                .execute(pool)
+                .await?;
+            filled += 1;
+        }
+    }
+    Ok(filled)
+}
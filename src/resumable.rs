@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use sqlx::PgPool;
+use tempfile::TempPath;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::configuration::ResumableSettings;
+use crate::error::ApiError;
+use crate::extract::hash_file;
+use crate::models::UploadSession;
+
+
+/// Claim the sessions `ids` of `username` for the request writing to or posting them, which
+/// then goes by the returned token. One request at a time, whichever instance it reaches.
+///
+/// A claim is a token on the rows rather than a lock held open, a chunk can take minutes. One
+/// left behind by a crashed instance runs out after `claim_seconds`. Sessions that are gone
+/// fail with 404 and those another request holds with 409, none are claimed then.
+pub async fn claim(
+    pool: &PgPool,
+    settings: &ResumableSettings,
+    ids: &[Uuid],
+    username: &str
+) -> Result<(Uuid, Vec<UploadSession>), ApiError> {
+    let token = Uuid::new_v4();
+    let ids: Vec<Uuid> = ids.iter().copied().collect::<HashSet<_>>().into_iter().collect();
+
+    let sessions = sqlx::query_as!(
+        UploadSession,
+        r#"
+        UPDATE upload_sessions
+        SET claimed_by = $3, claimed_until = current_timestamp + $4 * interval '1 second'
+        WHERE id = ANY($1) AND username = $2 AND expires_at > current_timestamp
+        AND (claimed_until IS NULL OR claimed_until <= current_timestamp)
+        RETURNING id, file_name, content_type, upload_length, upload_offset, expires_at
+        "#,
+        &ids[..],
+        username,
+        token,
+        settings.claim_seconds as f64
+    )
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
+
+    let missing = match ids.iter().find(|id| !sessions.iter().any(|session| session.id == **id)) {
+        Some(missing) => *missing,
+        None => return Ok((token, sessions))
+    };
+    release(pool, token).await;
+    let exists = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM upload_sessions
+            WHERE id = $1 AND username = $2 AND expires_at > current_timestamp
+        ) as "exists!"
+        "#,
+        missing,
+        username
+    )
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
+    Err(if exists {
+        ApiError::Conflict(format!("upload {} is being written to or posted", missing))
+    } else {
+        ApiError::NotFound(format!("upload {} not found", missing))
+    })
+}
+
+/// Give back the sessions claimed under `token`. Claims run out anyway, a failure is only logged.
+pub async fn release(pool: &PgPool, token: Uuid) {
+    let released = sqlx::query!(
+        r#"UPDATE upload_sessions SET claimed_by = NULL, claimed_until = NULL WHERE claimed_by = $1"#,
+        token
+    )
+        .execute(pool)
+        .await;
+    if let Err(e) = released {
+        tracing::error!("Failed to execute query {:?}", e);
+    }
+}
+
+/// Where the bytes of session `id` are written.
+pub fn session_path(settings: &ResumableSettings, id: Uuid) -> PathBuf {
+    Path::new(&settings.directory).join(id.to_string())
+}
+
+/// Hand the bytes of a complete session over as an upload, returns them and their SHA-256.
+///
+/// The upload is a hard link next to the session file, so the session survives a post that
+/// fails and can be finished again. Blocks on the whole file, run it off the async workers.
+pub fn link_session(settings: &ResumableSettings, id: Uuid) -> std::io::Result<(TempPath, String)> {
+    let session = session_path(settings, id);
+    let link = tempfile::Builder::new()
+        .prefix("poster-upload-")
+        .make_in(&settings.directory, |link| std::fs::hard_link(&session, link))?
+        .into_temp_path();
+
+    let sha256 = hash_file(&link)?;
+    Ok((link, sha256))
+}
+
+/// Remove the bytes of finished or abandoned sessions, a missing file is fine.
+pub async fn remove_session_files(settings: &ResumableSettings, ids: &[Uuid]) {
+    for id in ids {
+        match tokio::fs::remove_file(session_path(settings, *id)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!("Failed to remove the bytes of upload session {}: {}", id, e)
+        }
+    }
+}
+
+/// Remove expired sessions every `cleanup_interval_seconds`, forever.
+pub async fn run_cleanup(pool: PgPool, settings: ResumableSettings) {
+    let mut interval = tokio::time::interval(Duration::from_secs(settings.cleanup_interval_seconds));
+    loop {
+        interval.tick().await;
+        if let Err(e) = expire(&pool, &settings).await {
+            tracing::error!("Failed to remove expired upload sessions: {:?}", e);
+        }
+    }
+}
+
+#[instrument(name = "Removing expired upload sessions", skip_all)]
+async fn expire(pool: &PgPool, settings: &ResumableSettings) -> Result<(), sqlx::Error> {
+    let expired = sqlx::query_scalar!(
+        r#"DELETE FROM upload_sessions WHERE expires_at <= current_timestamp RETURNING id"#
+    )
+        .fetch_all(pool)
+        .await?;
+
+    if !expired.is_empty() {
+        tracing::info!("Removing {} expired upload session(s)", expired.len());
+    }
+    remove_session_files(settings, &expired).await;
+    Ok(())
+}
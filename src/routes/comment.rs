@@ -0,0 +1,242 @@
+use actix_web::{HttpResponse, web};
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::auth::AuthenticatedUser;
+use crate::error::ApiError;
+use crate::extract::Validated;
+use crate::models::{page_size, split_page, Comment, CommentCreate, CommentUpdate, CommentsPage, PostComments};
+use crate::routes::post::fetch_post_owner;
+use tracing::instrument;
+
+
+#[instrument(
+    name = "Creating a new comment",
+    skip(user, new_comment, pool),
+    fields(
+        post_id = %path.0,
+        username = %user.username
+    )
+)]
+pub async fn create_comment(
+    user: AuthenticatedUser,
+    path: web::Path<(Uuid,)>,
+    new_comment: Validated<web::Json<CommentCreate>>,
+    pool: web::Data<PgPool>
+) -> Result<HttpResponse, ApiError> {
+    let post_id = path.into_inner().0;
+
+    let comment = insert_comment(&pool, post_id, &user.username, &new_comment).await?
+        .ok_or_else(|| ApiError::NotFound("post not found".into()))?;
+
+    Ok(HttpResponse::Ok().json(comment))
+}
+
+/// Returns `None` when the post does not exist.
+#[instrument(
+    name = "Inserting the comment to the database",
+    skip(pool, username, new_comment)
+)]
+async fn insert_comment(
+    pool: &PgPool,
+    post_id: Uuid,
+    username: &str,
+    new_comment: &CommentCreate
+) -> Result<Option<Comment>, sqlx::Error> {
+
+    let result = sqlx::query_as!(
+        Comment,
+        r#"
+        INSERT INTO comments (id, post_id, username, body, created_at)
+        VALUES ($1, $2, $3, $4, DEFAULT)
+        RETURNING *
+        "#,
+        Uuid::new_v4(),
+        post_id,
+        username,
+        &new_comment.body
+    )
+        .fetch_one(pool)
+        .await;
+
+    match result {
+        Ok(comment) => Ok(Some(comment)),
+        // foreign_key_violation: the post is gone
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23503") => Ok(None),
+        Err(e) => {
+            tracing::error!("Failed to execute query {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+#[instrument(
+    name = "Fetching post comments from database",
+    skip(pool, page),
+    fields(
+        post_id = %path.0
+    )
+)]
+pub async fn get_comments(
+    path: web::Path<(Uuid,)>,
+    page: Validated<web::Query<CommentsPage>>,
+    pool: web::Data<PgPool>
+) -> Result<HttpResponse, ApiError> {
+    let post_id = path.into_inner().0;
+
+    sqlx::query!(
+        r#"SELECT id FROM posts WHERE id = $1"#,
+        post_id
+    )
+        .fetch_optional(pool.as_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?
+        .ok_or_else(|| ApiError::NotFound("post not found".into()))?;
+
+    let limit = page_size(page.limit);
+
+    let comments = sqlx::query_as!(
+        Comment,
+        r#"
+        SELECT * FROM comments
+        WHERE post_id = $1
+        AND ($2::timestamp IS NULL OR (created_at, id) > ($2, $3::uuid))
+        ORDER BY created_at, id
+        LIMIT $4
+        "#,
+        post_id,
+        page.cursor.map(|c| c.created_at),
+        page.cursor.map(|c| c.id),
+        limit + 1
+    )
+        .fetch_all(pool.as_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
+
+    let (comments, next_cursor) = split_page(comments, limit);
+
+    Ok(HttpResponse::Ok().json(PostComments { comments, next_cursor }))
+}
+
+#[instrument(
+    name = "Updating comment in the database",
+    skip(user, pool, update),
+    fields(
+        post_id = %path.0,
+        comment_id = %path.1,
+        username = %user.username
+    )
+)]
+pub async fn update_comment(
+    user: AuthenticatedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    update: Validated<web::Json<CommentUpdate>>,
+    pool: web::Data<PgPool>
+) -> Result<HttpResponse, ApiError> {
+    let (post_id, comment_id) = path.into_inner();
+
+    // Only the author may edit a comment
+    match fetch_comment_author(&pool, post_id, comment_id).await? {
+        Some(author) if author == user.username => {},
+        Some(_) => return Err(ApiError::Forbidden("comment belongs to another user".into())),
+        None => return Err(ApiError::NotFound("comment not found".into()))
+    }
+
+    let comment = sqlx::query_as!(
+        Comment,
+        r#"
+        UPDATE comments
+        SET body = $1, updated_at = current_timestamp
+        WHERE id = $2 AND post_id = $3 AND username = $4
+        RETURNING *
+        "#,
+        &update.body,
+        comment_id,
+        post_id,
+        &user.username
+    )
+        .fetch_optional(pool.as_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?
+        .ok_or_else(|| ApiError::NotFound("comment not found".into()))?;
+
+    Ok(HttpResponse::Ok().json(comment))
+}
+
+#[instrument(
+    name = "Deleting the comment",
+    skip(user, pool),
+    fields(
+        post_id = %path.0,
+        comment_id = %path.1,
+        username = %user.username
+    )
+)]
+pub async fn delete_comment(
+    user: AuthenticatedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<PgPool>
+) -> Result<HttpResponse, ApiError> {
+    let (post_id, comment_id) = path.into_inner();
+
+    // The author and the owner of the post may remove a comment
+    let author = fetch_comment_author(&pool, post_id, comment_id).await?
+        .ok_or_else(|| ApiError::NotFound("comment not found".into()))?;
+    if author != user.username {
+        match fetch_post_owner(&pool, post_id).await? {
+            Some(owner) if owner == user.username => {},
+            _ => return Err(ApiError::Forbidden("comment belongs to another user".into()))
+        }
+    }
+
+    let deleted = sqlx::query!(
+        r#"DELETE FROM comments WHERE id = $1 AND post_id = $2"#,
+        comment_id,
+        post_id
+    )
+        .execute(pool.as_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?
+        .rows_affected();
+
+    if deleted == 0 {
+        return Err(ApiError::NotFound("comment not found".into()))
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[instrument(
+    name = "Fetching comment author from database",
+    skip(pool)
+)]
+async fn fetch_comment_author(
+    pool: &PgPool,
+    post_id: Uuid,
+    comment_id: Uuid
+) -> Result<Option<String>, sqlx::Error> {
+    let rec = sqlx::query!(
+        r#"SELECT username FROM comments WHERE id = $1 AND post_id = $2"#,
+        comment_id,
+        post_id
+    )
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
+
+    Ok(rec.map(|r| r.username))
+}
@@ -0,0 +1,178 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use actix_web::http::header::{
+    CacheControl, CacheDirective, ContentType, EntityTag, ETag, HttpDate, IfModifiedSince, IfNoneMatch, LastModified
+};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, NaiveDateTime};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::configuration::FileUrlSettings;
+use crate::error::ApiError;
+use crate::extract::to_hex;
+use crate::signing::url_period_start;
+
+
+/// Answer `body` as JSON along with validators, or with 304 when the copy the client
+/// revalidates is still current.
+///
+/// The ETag hashes the body, so it catches every change, signed URLs that moved on to the
+/// next period included. Only pass `last_modified` when it covers every change too, see
+/// `urls_modified`, lists for one have none as a post deleted from them leaves no trace.
+/// `If-None-Match` wins when both are sent, as HTTP has it.
+pub(crate) fn json_with_validators<T: Serialize>(
+    req: &HttpRequest,
+    body: &T,
+    last_modified: Option<NaiveDateTime>
+) -> Result<HttpResponse, ApiError> {
+    let json = serde_json::to_vec(body)
+        .map_err(|e| ApiError::Internal(format!("failed to serialize the response: {}", e)))?;
+    let etag = EntityTag::new_strong(to_hex(&Sha256::digest(&json)));
+    // Whole seconds, like the header, or a copy would never be as new as the row
+    let last_modified = last_modified
+        .map(|at| HttpDate::from(UNIX_EPOCH + Duration::from_secs(at.and_utc().timestamp().max(0) as u64)));
+
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => match (req.get_header::<IfModifiedSince>(), last_modified) {
+            (Some(IfModifiedSince(since)), Some(modified)) => modified <= since,
+            _ => false
+        }
+    };
+
+    let mut response = if not_modified { HttpResponse::NotModified() } else { HttpResponse::Ok() };
+    response
+        .insert_header(ETag(etag))
+        // Always revalidated, the like counts move
+        .insert_header(CacheControl(vec![CacheDirective::Private, CacheDirective::NoCache]));
+    if let Some(modified) = last_modified {
+        response.insert_header(LastModified(modified));
+    }
+
+    Ok(if not_modified {
+        response.finish()
+    } else {
+        response.insert_header(ContentType::json()).body(json)
+    })
+}
+
+/// Last change of a body holding `updated_at` and the signed URLs of its media, which are
+/// handed out anew at the start of every `file_urls` period.
+pub(crate) fn urls_modified(updated_at: NaiveDateTime, file_urls: &FileUrlSettings) -> NaiveDateTime {
+    let period_start = url_period_start(file_urls.expiry_seconds) as i64;
+    let period_start = DateTime::from_timestamp(period_start, 0).map(|at| at.naive_utc());
+    updated_at.max(period_start.unwrap_or(updated_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use chrono::NaiveDate;
+    use secrecy::Secret;
+    use serde_json::json;
+    use super::*;
+
+    fn at(hour: u32, second: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2022, 8, 30).unwrap().and_hms_opt(hour, 0, second).unwrap()
+    }
+
+    fn header(response: &HttpResponse, name: actix_web::http::header::HeaderName) -> String {
+        response.headers().get(name).unwrap().to_str().unwrap().to_string()
+    }
+
+    /// Response to `req` for the same body and change date as `first`.
+    fn again(req: TestRequest) -> HttpResponse {
+        json_with_validators(&req.to_http_request(), &json!({ "caption": "hi" }), Some(at(12, 0))).unwrap()
+    }
+
+    fn first() -> HttpResponse {
+        again(TestRequest::default())
+    }
+
+    #[test]
+    fn matching_etags_are_not_modified() {
+        let first = first();
+        assert_eq!(first.status(), StatusCode::OK);
+        let etag = header(&first, ETAG);
+
+        let response = again(TestRequest::default().insert_header((IF_NONE_MATCH, etag.clone())));
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(header(&response, ETAG), etag);
+
+        // Weak comparison, and any of a list
+        let weak = format!("W/{}", etag);
+        assert_eq!(again(TestRequest::default().insert_header((IF_NONE_MATCH, weak))).status(), StatusCode::NOT_MODIFIED);
+        let list = format!("\"other\", {}", etag);
+        assert_eq!(again(TestRequest::default().insert_header((IF_NONE_MATCH, list))).status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(again(TestRequest::default().insert_header((IF_NONE_MATCH, "*"))).status(), StatusCode::NOT_MODIFIED);
+
+        assert_eq!(again(TestRequest::default().insert_header((IF_NONE_MATCH, "\"other\""))).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn unchanged_copies_are_not_modified_since() {
+        let last_modified = header(&first(), LAST_MODIFIED);
+        assert_eq!(last_modified, "Tue, 30 Aug 2022 12:00:00 GMT");
+
+        let since = |date: &str| again(TestRequest::default().insert_header((IF_MODIFIED_SINCE, date))).status();
+        assert_eq!(since(&last_modified), StatusCode::NOT_MODIFIED);
+        assert_eq!(since("Tue, 30 Aug 2022 13:00:00 GMT"), StatusCode::NOT_MODIFIED);
+        assert_eq!(since("Tue, 30 Aug 2022 11:59:59 GMT"), StatusCode::OK);
+    }
+
+    #[test]
+    fn if_none_match_wins_over_if_modified_since() {
+        let first = first();
+        let etag = header(&first, ETAG);
+        let last_modified = header(&first, LAST_MODIFIED);
+
+        let stale_etag = again(TestRequest::default()
+            .insert_header((IF_NONE_MATCH, "\"other\""))
+            .insert_header((IF_MODIFIED_SINCE, last_modified)));
+        assert_eq!(stale_etag.status(), StatusCode::OK);
+
+        let current_etag = again(TestRequest::default()
+            .insert_header((IF_NONE_MATCH, etag))
+            .insert_header((IF_MODIFIED_SINCE, "Tue, 30 Aug 2022 11:00:00 GMT")));
+        assert_eq!(current_etag.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[test]
+    fn lists_are_only_checked_by_etag() {
+        let req = TestRequest::default()
+            .insert_header((IF_MODIFIED_SINCE, "Tue, 30 Aug 2022 13:00:00 GMT"))
+            .to_http_request();
+        let response = json_with_validators(&req, &json!([]), None).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(LAST_MODIFIED).is_none());
+    }
+
+    #[test]
+    fn edits_change_the_validators() {
+        let first = first();
+        let req = TestRequest::default()
+            .insert_header((IF_NONE_MATCH, header(&first, ETAG)))
+            .insert_header((IF_MODIFIED_SINCE, header(&first, LAST_MODIFIED)))
+            .to_http_request();
+
+        let edited = json_with_validators(&req, &json!({ "caption": "hello" }), Some(at(12, 5))).unwrap();
+        assert_eq!(edited.status(), StatusCode::OK);
+        assert_ne!(header(&edited, ETAG), header(&first, ETAG));
+        assert_eq!(header(&edited, LAST_MODIFIED), "Tue, 30 Aug 2022 12:00:05 GMT");
+    }
+
+    #[test]
+    fn signed_urls_move_last_modified_forward() {
+        let file_urls = FileUrlSettings { secret: Secret::new("secret".into()), expiry_seconds: 3600 };
+        let period_start = url_period_start(file_urls.expiry_seconds) as i64;
+
+        // Edited before the current period, the URLs handed out are newer
+        assert_eq!(urls_modified(at(12, 0), &file_urls).and_utc().timestamp(), period_start);
+        // Edited since
+        let edited = DateTime::from_timestamp(period_start + 1, 0).unwrap().naive_utc();
+        assert_eq!(urls_modified(edited, &file_urls), edited);
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime};
+
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse, web};
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use sqlx::PgPool;
+use tokio::io::AsyncWriteExt;
+use tracing::instrument;
+use uuid::Uuid;
+use crate::auth::AuthenticatedUser;
+use crate::configuration::{DirectUploadSettings, MediaSettings, UploadFormat};
+use crate::error::{ApiError, LOCK_NOT_AVAILABLE};
+use crate::extract::{hash_file, FormFile, Validated};
+use crate::media::MediaError;
+use crate::models::{DirectUpload, DirectUploadCreate, MediaCreate, PostCreate, PostFromUploads};
+use crate::routes::post::create_post;
+use crate::signing::Signer;
+use crate::storage::{incoming_key, Storage, StorageError};
+
+
+/// Hands out a URL the client `PUT`s the file to, so its bytes never go through a worker.
+/// The storage backend presigns it when it can, otherwise it points at `receive_direct_upload`.
+#[instrument(
+    name = "Issuing a direct upload",
+    skip(user, upload, pool, storage, media, settings),
+    fields(
+        username = %user.username,
+        size = upload.size
+    )
+)]
+pub async fn create_direct_upload(
+    user: AuthenticatedUser,
+    upload: Validated<web::Json<DirectUploadCreate>>,
+    pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
+    media: web::Data<MediaSettings>,
+    settings: web::Data<DirectUploadSettings>
+) -> Result<HttpResponse, ApiError> {
+    // Turned down now rather than after the bytes were sent
+    let format = UploadFormat::from_mime(&upload.content_type)
+        .filter(|format| media.accepted_formats.contains(format))
+        .ok_or_else(|| MediaError::UnsupportedFormat(upload.content_type.clone()))?;
+    let max = if format.is_video() { media.max_video_bytes } else { media.max_bytes };
+    if upload.size > max as u64 {
+        return Err(MediaError::TooLarge { size: upload.size as usize, max }.into())
+    }
+
+    let id = Uuid::new_v4();
+    let expires_in = Duration::from_secs(settings.expiry_seconds);
+    let expires_at = SystemTime::now() + expires_in;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO direct_uploads (id, username, content_type, size, sha256, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        id,
+        &user.username,
+        upload.content_type,
+        upload.size as i64,
+        upload.sha256.as_ref().map(|sha256| sha256.to_lowercase()),
+        DateTime::<Utc>::from(expires_at).naive_utc()
+    )
+        .execute(pool.as_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
+
+    let url = match storage.presign_put(&incoming_key(id), expires_in).await? {
+        Some(url) => url,
+        None => {
+            let token = Signer::new(settings.secret.clone()).sign(&id.to_string(), expires_at);
+            format!("/uploads/{}", token)
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(DirectUpload {
+        upload_id: id,
+        method: "PUT",
+        url,
+        headers: BTreeMap::from([("Content-Type", upload.content_type.clone())]),
+        expires_at: DateTime::<Utc>::from(expires_at).naive_utc()
+    }))
+}
+
+/// Stands in for a presigned URL on backends without them. The token is the credential,
+/// the request carries no session.
+#[instrument(
+    name = "Receiving a direct upload",
+    skip(req, path, body, pool, storage, settings),
+    fields(
+        upload_id = tracing::field::Empty
+    )
+)]
+pub async fn receive_direct_upload(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    mut body: web::Payload,
+    pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
+    settings: web::Data<DirectUploadSettings>
+) -> Result<HttpResponse, ApiError> {
+    let token = path.into_inner().0;
+    let id: Uuid = Signer::new(settings.secret.clone()).verify(&token)
+        .and_then(|payload| payload.parse().ok())
+        .ok_or_else(|| ApiError::Forbidden("the upload URL is invalid or has expired".into()))?;
+    tracing::Span::current().record("upload_id", tracing::field::display(id));
+
+    let upload = sqlx::query!(
+        r#"SELECT content_type, size FROM direct_uploads WHERE id = $1"#,
+        id
+    )
+        .fetch_optional(pool.as_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?
+        .ok_or_else(|| ApiError::NotFound("upload not found".into()))?;
+
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    if content_type != Some(upload.content_type.as_str()) {
+        return Err(ApiError::UnsupportedMediaType(format!("the upload was announced as {}", upload.content_type)))
+    }
+    let size = upload.size as u64;
+    let too_large = || ApiError::PayloadTooLarge(format!("the upload was announced as {} bytes", size));
+    let announced = req.headers().get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if announced.is_some_and(|announced| announced > size) {
+        return Err(too_large())
+    }
+
+    let write_failed = |e: std::io::Error| ApiError::Internal(format!("failed to write a temporary file: {}", e));
+    let temp = web::block(|| tempfile::Builder::new().prefix("poster-upload-").tempfile())
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))?
+        .map_err(write_failed)?;
+    let (file, temp_path) = temp.into_parts();
+    let mut file = tokio::fs::File::from_std(file);
+
+    let mut received = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| ApiError::BadRequest(format!("the upload was cut short: {}", e)))?;
+        received += chunk.len() as u64;
+        if received > size {
+            return Err(too_large())
+        }
+        file.write_all(&chunk).await.map_err(write_failed)?;
+    }
+    file.flush().await.map_err(write_failed)?;
+
+    storage.put_file(&incoming_key(id), &temp_path, &upload.content_type).await
+        .map_err(|e| {
+            tracing::error!("Unable to save file {} : {:?}", incoming_key(id), e);
+            e
+        })?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Checks what arrived in storage against what was announced, then posts it.
+///
+/// The uploads are locked until the post is there and they are removed along with the
+/// lock, so two requests can't both post them. Failing before that leaves them to retry.
+#[instrument(
+    name = "Creating a post from direct uploads",
+    skip(user, new_post, pool, storage, media),
+    fields(
+        username = %user.username,
+        media_count = new_post.media.len()
+    )
+)]
+pub async fn finish_direct_upload(
+    user: AuthenticatedUser,
+    new_post: Validated<web::Json<PostFromUploads>>,
+    pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
+    media: web::Data<MediaSettings>
+) -> Result<HttpResponse, ApiError> {
+    let new_post = new_post.into_inner().into_inner();
+    let ids: Vec<Uuid> = new_post.media.iter().map(|item| item.upload_id).collect();
+
+    let mut claim = pool.begin().await?;
+    let uploads = sqlx::query!(
+        r#"
+        SELECT id, content_type, size, sha256
+        FROM direct_uploads
+        WHERE id = ANY($1) AND username = $2
+        FOR UPDATE NOWAIT
+        "#,
+        &ids[..],
+        &user.username
+    )
+        .fetch_all(&mut claim)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(e) if e.code().as_deref() == Some(LOCK_NOT_AVAILABLE) =>
+                ApiError::Conflict("the uploads are being posted".into()),
+            e => {
+                tracing::error!("Failed to execute query {:?}", e);
+                e.into()
+            }
+        })?;
+    let uploads: HashMap<Uuid, _> = uploads.into_iter()
+        .map(|upload| (upload.id, upload))
+        .collect();
+
+    let mut items = Vec::with_capacity(new_post.media.len());
+    for item in new_post.media {
+        let upload = uploads.get(&item.upload_id)
+            .ok_or_else(|| ApiError::NotFound(format!("upload {} not found", item.upload_id)))?;
+
+        let temp = web::block(|| tempfile::Builder::new().prefix("poster-upload-").tempfile())
+            .await
+            .map_err(|e| ApiError::Internal(e.to_string()))?
+            .map_err(|e| ApiError::Internal(format!("failed to create a temporary file: {}", e)))?
+            .into_temp_path();
+        match storage.get_file(&incoming_key(upload.id), &temp).await {
+            Ok(()) => {}
+            Err(StorageError::NotFound(_)) =>
+                return Err(ApiError::Conflict(format!("upload {} has not been received", upload.id))),
+            Err(e) => return Err(e.into())
+        }
+
+        let path = temp.to_path_buf();
+        let (size, sha256) = web::block(move || Ok::<_, std::io::Error>((std::fs::metadata(&path)?.len(), hash_file(&path)?)))
+            .await
+            .map_err(|e| ApiError::Internal(e.to_string()))?
+            .map_err(|e| ApiError::Internal(format!("failed to read upload {}: {}", upload.id, e)))?;
+        // Presigned URLs can't hold the client to what it announced, so it is checked here
+        if size != upload.size as u64 {
+            return Err(ApiError::BadRequest(format!(
+                "upload {} is {} bytes, {} were announced", upload.id, size, upload.size
+            )))
+        }
+        if upload.sha256.as_ref().is_some_and(|announced| *announced != sha256) {
+            return Err(ApiError::BadRequest(format!("upload {} does not match its announced SHA-256", upload.id)))
+        }
+
+        items.push(MediaCreate {
+            img_file: FormFile {
+                file_name: upload.id.to_string(),
+                content_type: upload.content_type.clone(),
+                path: temp,
+                size,
+                sha256
+            },
+            alt_text: item.alt_text.filter(|alt| !alt.is_empty())
+        });
+    }
+
+    let post = PostCreate {
+        media: items,
+        caption: new_post.caption
+    };
+    let id = create_post(&pool, storage.get_ref(), media.into_inner(), &user.username, &post).await?;
+
+    let removed = sqlx::query!(
+        r#"DELETE FROM direct_uploads WHERE id = ANY($1)"#,
+        &ids[..]
+    )
+        .execute(&mut claim)
+        .await;
+    if let Err(e) = match removed {
+        Ok(_) => claim.commit().await,
+        Err(e) => Err(e)
+    } {
+        // The post is there, the reaper removes what is left
+        tracing::error!("Failed to execute query {:?}", e);
+        return Ok(HttpResponse::Ok().json(id))
+    }
+    for id in &ids {
+        match storage.delete(&incoming_key(*id)).await {
+            // The same upload listed twice
+            Ok(()) | Err(StorageError::NotFound(_)) => {}
+            Err(e) => tracing::warn!("Leaving direct upload {} to the reaper: {}", id, e)
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(id))
+}
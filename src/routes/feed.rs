@@ -1,52 +1,59 @@
-use actix_web::{HttpResponse, Responder, web};
-use crate::models::{FeedFollowing, LatestPosts, Post};
+use actix_web::{HttpRequest, HttpResponse, web};
+use futures_util::future::try_join_all;
+use crate::configuration::FileUrlSettings;
+use crate::error::ApiError;
+use crate::extract::Validated;
+use crate::models::{page_size, split_page, FeedFollowing, LatestPosts, PostRow};
+use crate::routes::conditional::json_with_validators;
+use crate::routes::post::with_media;
+use crate::storage::Storage;
 use sqlx::PgPool;
 use tracing::instrument;
 
+/// Fetched with `POST`, clients still revalidate it with `If-None-Match`.
 #[instrument(
     name = "Getting latest posts",
-    skip(feed, pool)
+    skip(req, feed, pool, storage, file_urls)
 )]
-pub async fn get_latest(feed: web::Json<FeedFollowing>, pool: web::Data<PgPool>) -> impl Responder {
+pub async fn get_latest(
+    req: HttpRequest,
+    feed: Validated<web::Json<FeedFollowing>>,
+    pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
+    file_urls: web::Data<FileUrlSettings>
+) -> Result<HttpResponse, ApiError> {
 
-    let query_result = sqlx::query!(
+    let limit = page_size(feed.limit);
+
+    let rows = sqlx::query_as!(
+        PostRow,
         r#"
-        SELECT * FROM posts
+        SELECT id, username, caption, likes, created_at, updated_at, blurhash, dominant_color
+        FROM posts
         WHERE username = ANY($1)
-        ORDER BY created_at
-        LIMIT 10 OFFSET $2
+        AND ($2::timestamp IS NULL OR (created_at, id) < ($2, $3::uuid))
+        ORDER BY created_at DESC, id DESC
+        LIMIT $4
         "#,
         &feed.followings[..],
-        feed.page as i64 * 10
+        feed.cursor.map(|c| c.created_at),
+        feed.cursor.map(|c| c.id),
+        limit + 1
     )
         .fetch_all(pool.as_ref())
-        .await;
-
-    let records = match query_result {
-        Ok(r) => r,
-        Err(e) => {
+        .await
+        .map_err(|e| {
             tracing::error!("Failed to execute query {:?}", e);
-            return HttpResponse::InternalServerError().finish()
-        }
-    };
-
-    let posts: Vec<Post> = records.into_iter()
-        .map(|r| {
-            Post {
-                id: r.id,
-                username: r.username,
-                img_url: r.img_url,
-                caption: r.caption,
-                likes: r.likes,
-                created_at: r.created_at
-            }
-        }).collect();
+            e
+        })?;
 
+    let (rows, next_cursor) = split_page(rows, limit);
+    let posts = with_media(pool.as_ref(), rows).await?;
 
     let latest = LatestPosts {
-        posts
+        posts: try_join_all(posts.into_iter().map(|p| p.with_public_url(storage.get_ref(), &file_urls))).await?,
+        next_cursor
     };
 
-    HttpResponse::Ok()
-        .json(latest)
+    json_with_validators(&req, &latest, None)
 }
@@ -1,8 +1,50 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use actix_files::NamedFile;
-use actix_web::{HttpRequest, Result};
-use std::path::PathBuf;
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse, web};
+use tracing::instrument;
+use crate::configuration::FileUrlSettings;
+use crate::error::ApiError;
+use crate::models::SignedFile;
+use crate::signing::Signer;
+use crate::storage::{Storage, StorageError};
+
+
+/// Serves files of the local storage backend to whoever holds a URL `LocalStorage::url` signed.
+#[instrument(
+    name = "Serving a file",
+    skip(req, path, query, storage, settings),
+    fields(
+        key = %path.0
+    )
+)]
+pub async fn serve_file(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    query: web::Query<SignedFile>,
+    storage: web::Data<dyn Storage>,
+    settings: web::Data<FileUrlSettings>
+) -> Result<HttpResponse, ApiError> {
+    let key = path.into_inner().0;
+    let signer = Signer::new(settings.secret.clone());
+    let expires = match (query.expires, &query.signature) {
+        (Some(expires), Some(signature)) if signer.verify_signature(&key, expires, signature) => expires,
+        _ => return Err(ApiError::Forbidden("the file URL is invalid or has expired".into()))
+    };
+
+    let path = storage.local_path(&key)
+        .ok_or_else(|| ApiError::NotFound("file not found".into()))?;
+    let file = NamedFile::open_async(path).await
+        .map_err(StorageError::from)?;
 
-pub async fn files(req: HttpRequest) -> Result<NamedFile> {
-    let path: PathBuf = req.match_info().query("filename").parse().unwrap();
-    Ok(NamedFile::open(path)?)
+    // Keys name their content, so the file can be cached for as long as the URL is valid
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut response = file.into_response(&req);
+    response.headers_mut().insert(
+        header::CACHE_CONTROL,
+        header::HeaderValue::from_str(&format!("private, max-age={}", expires.saturating_sub(now)))
+            .expect("a valid header value")
+    );
+    Ok(response)
 }
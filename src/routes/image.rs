@@ -0,0 +1,120 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::header::{CacheControl, CacheDirective, ContentType, EntityTag, ETag, IfNoneMatch};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, web};
+use sqlx::PgPool;
+use sqlx::types::Json;
+use tracing::instrument;
+use uuid::Uuid;
+use crate::configuration::{FileUrlSettings, ImageSettings};
+use crate::error::ApiError;
+use crate::image_cache::ImageCache;
+use crate::media::{transform, Transform};
+use crate::models::{image_payload, ImageQuery, MediaKind, Variants};
+use crate::placeholders::still_key;
+use crate::signing::Signer;
+use crate::storage::Storage;
+
+
+/// Resizes, crops and converts stored images to whoever holds a URL `Post::with_public_url`
+/// signed. Renditions are cached on disk and, as they never change, by clients for as long
+/// as the URL is valid.
+#[allow(clippy::too_many_arguments)]
+#[instrument(
+    name = "Serving a transformed image",
+    skip(req, path, query, pool, storage, cache, settings, file_urls),
+    fields(
+        image_id = %path.0
+    )
+)]
+pub async fn serve_image(
+    req: HttpRequest,
+    path: web::Path<(Uuid,)>,
+    query: web::Query<ImageQuery>,
+    pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
+    cache: web::Data<ImageCache>,
+    settings: web::Data<ImageSettings>,
+    file_urls: web::Data<FileUrlSettings>
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner().0;
+    let query = query.into_inner();
+    let signer = Signer::new(file_urls.secret.clone());
+    let expires = match (query.expires, &query.signature) {
+        (Some(expires), Some(signature)) if signer.verify_signature(&image_payload(id), expires, signature) => expires,
+        _ => return Err(ApiError::Forbidden("the image URL is invalid or has expired".into()))
+    };
+    if [query.w, query.h].iter().flatten().any(|size| !settings.sizes.contains(size)) {
+        return Err(ApiError::BadRequest(format!("w and h must be one of {:?}", settings.sizes)))
+    }
+    let rendition = Transform {
+        width: query.w,
+        height: query.h,
+        fit: query.fit,
+        format: query.format
+    };
+
+    let blob = sqlx::query!(
+        r#"
+        SELECT sha256, kind as "kind: MediaKind", img_url, variants as "variants: Json<Variants>"
+        FROM blobs
+        WHERE id = $1
+        "#,
+        id
+    )
+        .fetch_optional(pool.as_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?
+        .ok_or_else(|| ApiError::NotFound("image not found".into()))?;
+    // The largest poster stands in for a video
+    let source = still_key(blob.kind, &blob.img_url, &blob.variants)
+        .ok_or_else(|| ApiError::NotFound("the video has no poster".into()))?;
+
+    // Images from before hashes were taken go by their id, which never names other content either
+    let content = blob.sha256.unwrap_or_else(|| id.to_string());
+    let name = format!("{}-{}", content, rendition.name());
+    let etag = EntityTag::new_strong(name.clone());
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let cache_control = CacheControl(vec![
+        CacheDirective::Private,
+        CacheDirective::MaxAge(expires.saturating_sub(now) as u32),
+        CacheDirective::Extension("immutable".into(), None)
+    ]);
+
+    let fresh = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false
+    };
+    if fresh {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .insert_header(cache_control)
+            .finish())
+    }
+
+    let io_error = |e: std::io::Error| ApiError::Internal(format!("image cache: {}", e));
+    let data = match cache.get(&name).await.map_err(io_error)? {
+        Some(data) => data,
+        None => {
+            let original = storage.get(source).await?;
+            let quality = settings.quality;
+            let data = web::block(move || transform(&original, &rendition, quality))
+                .await
+                .map_err(|e| ApiError::Internal(e.to_string()))??;
+            if let Err(e) = cache.insert(&name, &data).await {
+                tracing::warn!("Failed to cache image {}: {}", name, e);
+            }
+            data
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header(ContentType(rendition.format.content_type().parse().expect("a valid MIME type")))
+        .insert_header(ETag(etag))
+        .insert_header(cache_control)
+        .body(data))
+}
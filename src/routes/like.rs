@@ -0,0 +1,227 @@
+use actix_web::{HttpResponse, web};
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::auth::AuthenticatedUser;
+use crate::error::ApiError;
+use crate::models::{Like, LikeCount, PostLikes};
+use tracing::instrument;
+
+
+#[instrument(
+    name = "Liking a post",
+    skip(user, pool),
+    fields(
+        post_id = %path.0,
+        username = %user.username
+    )
+)]
+pub async fn like_post(
+    user: AuthenticatedUser,
+    path: web::Path<(Uuid,)>,
+    pool: web::Data<PgPool>
+) -> Result<HttpResponse, ApiError> {
+    let post_id = path.into_inner().0;
+
+    let likes = add_like(&pool, post_id, &user.username).await?
+        .ok_or_else(|| ApiError::NotFound("post not found".into()))?;
+
+    Ok(HttpResponse::Ok().json(LikeCount { likes }))
+}
+
+/// Record the like and bump `posts.likes` in one transaction.
+///
+/// Liking twice is a no-op. Returns `None` when the post does not exist.
+#[instrument(
+    name = "Inserting the like to the database",
+    skip(pool, username)
+)]
+async fn add_like(
+    pool: &PgPool,
+    post_id: Uuid,
+    username: &str
+) -> Result<Option<i32>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // Lock the post row so concurrent likes/unlikes serialize on the counter
+    let post = sqlx::query!(
+        r#"SELECT id FROM posts WHERE id = $1 FOR UPDATE"#,
+        post_id
+    )
+        .fetch_optional(&mut tx)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
+    if post.is_none() {
+        return Ok(None)
+    }
+
+    let inserted = sqlx::query!(
+        r#"
+        INSERT INTO post_likes (post_id, username, created_at)
+        VALUES ($1, $2, DEFAULT)
+        ON CONFLICT DO NOTHING
+        "#,
+        post_id,
+        username
+    )
+        .execute(&mut tx)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?
+        .rows_affected();
+
+    let rec = sqlx::query!(
+        r#"
+        UPDATE posts
+        SET likes = likes + $2,
+            updated_at = CASE WHEN $2 = 0 THEN updated_at ELSE current_timestamp END
+        WHERE id = $1
+        RETURNING likes
+        "#,
+        post_id,
+        inserted as i32
+    )
+        .fetch_one(&mut tx)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
+
+    tx.commit().await?;
+
+    Ok(Some(rec.likes))
+}
+
+#[instrument(
+    name = "Unliking a post",
+    skip(user, pool),
+    fields(
+        post_id = %path.0,
+        username = %user.username
+    )
+)]
+pub async fn unlike_post(
+    user: AuthenticatedUser,
+    path: web::Path<(Uuid,)>,
+    pool: web::Data<PgPool>
+) -> Result<HttpResponse, ApiError> {
+    let post_id = path.into_inner().0;
+
+    let likes = remove_like(&pool, post_id, &user.username).await?
+        .ok_or_else(|| ApiError::NotFound("post not found".into()))?;
+
+    Ok(HttpResponse::Ok().json(LikeCount { likes }))
+}
+
+/// Remove the like and decrement `posts.likes` in one transaction.
+///
+/// Unliking a post that was not liked is a no-op. Returns `None` when the post does not exist.
+#[instrument(
+    name = "Deleting the like from the database",
+    skip(pool, username)
+)]
+async fn remove_like(
+    pool: &PgPool,
+    post_id: Uuid,
+    username: &str
+) -> Result<Option<i32>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let post = sqlx::query!(
+        r#"SELECT id FROM posts WHERE id = $1 FOR UPDATE"#,
+        post_id
+    )
+        .fetch_optional(&mut tx)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
+    if post.is_none() {
+        return Ok(None)
+    }
+
+    let deleted = sqlx::query!(
+        r#"DELETE FROM post_likes WHERE post_id = $1 AND username = $2"#,
+        post_id,
+        username
+    )
+        .execute(&mut tx)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?
+        .rows_affected();
+
+    let rec = sqlx::query!(
+        r#"
+        UPDATE posts
+        SET likes = likes - $2,
+            updated_at = CASE WHEN $2 = 0 THEN updated_at ELSE current_timestamp END
+        WHERE id = $1
+        RETURNING likes
+        "#,
+        post_id,
+        deleted as i32
+    )
+        .fetch_one(&mut tx)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
+
+    tx.commit().await?;
+
+    Ok(Some(rec.likes))
+}
+
+#[instrument(
+    name = "Fetching post likes from database",
+    skip(pool),
+    fields(
+        post_id = %path.0
+    )
+)]
+pub async fn get_likes(
+    path: web::Path<(Uuid,)>,
+    pool: web::Data<PgPool>
+) -> Result<HttpResponse, ApiError> {
+    let post_id = path.into_inner().0;
+
+    sqlx::query!(
+        r#"SELECT id FROM posts WHERE id = $1"#,
+        post_id
+    )
+        .fetch_optional(pool.as_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?
+        .ok_or_else(|| ApiError::NotFound("post not found".into()))?;
+
+    let likes = sqlx::query_as!(
+        Like,
+        r#"
+        SELECT username, created_at FROM post_likes
+        WHERE post_id = $1
+        ORDER BY created_at
+        "#,
+        post_id
+    )
+        .fetch_all(pool.as_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
+
+    Ok(HttpResponse::Ok().json(PostLikes { likes }))
+}
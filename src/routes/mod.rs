@@ -1,43 +1,103 @@
-mod post;
+pub(crate) mod post;
 mod feed;
 mod files;
+mod image;
+mod like;
+mod comment;
+mod conditional;
+mod resumable;
+mod direct;
 
-use actix_cors::Cors;
-
-use actix_web::{HttpResponse, web};
+use actix_web::{guard, HttpResponse, web};
 use actix_web::web::ServiceConfig;
 use crate::auth::Author;
+use crate::error::ApiError;
 use crate::routes::feed::get_latest;
+use crate::routes::like::{get_likes, like_post, unlike_post};
+use crate::routes::comment::{create_comment, delete_comment, get_comments, update_comment};
 use crate::routes::post::{delete_post, get_post, upload_post, update_post, get_use_posts, get_single_post};
-use actix_files as fs;
+use crate::routes::files::serve_file;
+use crate::routes::image::serve_image;
+use crate::routes::direct::{create_direct_upload, finish_direct_upload, receive_direct_upload};
+use crate::routes::resumable::{append_chunk, cancel_upload, create_upload, finish_upload, upload_status};
 
 pub fn app_config(config: &mut ServiceConfig) {
 
     let health_resource = web::resource("/")
         .route(web::get().to(health));
 
+    // Reading posts, likes and comments needs no login. The guard lets every other method
+    // fall through to the scope below
+    let posts_read_resource = web::scope("/posts")
+        .guard(guard::Get())
+        .route("", web::get().to(get_post))
+        .route("/{username}", web::get().to(get_use_posts))
+        .route("/{id}/likes", web::get().to(get_likes))
+        .route("/{id}/comments", web::get().to(get_comments));
+
     let posts_resource = web::scope("/posts")
-//        .wrap(Author)
+        .wrap(Author)
         .route("", web::post().to(upload_post))
         .route("", web::delete().to(delete_post))
-        .route("", web::get().to(get_post))
-        .route("/{username}", web::get().to(get_use_posts))
+        .route("/{id}/like", web::post().to(like_post))
+        .route("/{id}/like", web::delete().to(unlike_post))
+        .route("/{id}/comments", web::post().to(create_comment))
+        .route("/{id}/comments/{comment_id}", web::patch().to(update_comment))
+        .route("/{id}/comments/{comment_id}", web::delete().to(delete_comment))
         .route("", web::patch().to(update_post));
 
+    let resumable_resource = web::scope("/resumable")
+        .wrap(Author)
+        .route("", web::post().to(create_upload))
+        .route("/posts", web::post().to(finish_upload))
+        .route("/{id}", web::head().to(upload_status))
+        .route("/{id}", web::patch().to(append_chunk))
+        .route("/{id}", web::delete().to(cancel_upload));
+
+    // The signed token authorizes the upload, it sits outside of `Author`. The guard lets
+    // other methods fall through to the scope below, `/uploads/posts` included
+    let direct_upload_resource = web::resource("/uploads/{token}")
+        .guard(guard::Put())
+        .route(web::put().to(receive_direct_upload));
+
+    let direct_uploads_resource = web::scope("/uploads")
+        .wrap(Author)
+        .route("", web::post().to(create_direct_upload))
+        .route("/posts", web::post().to(finish_direct_upload));
+
     let post_resource = web::scope("/post")
         .route("/{id}", web::get().to(get_single_post));
 
-//    let static_files = web::scope("/files")
-//        .route("/{filename}", web::get().to(files));
+    // Only reachable through signed URLs, see `LocalStorage::url`
+    let files_resource = web::resource("/files/{key:.*}")
+        .route(web::get().to(serve_file))
+        .route(web::head().to(serve_file));
+
+    let image_resource = web::resource("/img/{id}")
+        .route(web::get().to(serve_image));
 
     let feed_resource = web::resource("/latest")
-//        .wrap(Author)
+        .wrap(Author)
         .route(web::post().to(get_latest));
 
+    // Malformed bodies, queries and paths get the same JSON error shape as handler errors
+    config.app_data(web::JsonConfig::default()
+        .error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()));
+    config.app_data(web::QueryConfig::default()
+        .error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()));
+    config.app_data(web::PathConfig::default()
+        .error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()));
+
     config.service(health_resource);
+    config.service(posts_read_resource);
     config.service(posts_resource);
+    config.service(resumable_resource);
+    config.service(direct_upload_resource);
+    config.service(direct_uploads_resource);
     config.service(post_resource);
-    config.service(fs::Files::new("/files","./files").show_files_listing());
+    // `NamedFile` answers `Range` and `If-Range` requests, which is what lets clients stream videos
+    config.service(files_resource);
+    config.service(image_resource);
     config.service(feed_resource);
 }
 
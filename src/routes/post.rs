@@ -1,295 +1,743 @@
-use std::str::FromStr;
-use actix_extract_multipart::{File, Multipart};
-use actix_web::{HttpResponse, Responder, web};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use actix_web::{HttpRequest, HttpResponse, web};
+use futures_util::future::try_join_all;
 use sqlx::PgPool;
+use sqlx::types::Json;
 use uuid::Uuid;
-use crate::models::{PostID, PostCreate, Post, PostUpdate, UserPosts};
+use crate::auth::AuthenticatedUser;
+use crate::configuration::{FileUrlSettings, MediaSettings};
+use crate::error::ApiError;
+use crate::extract::{Form, FormFile, Validated};
+use crate::media::{self, MediaError, Placeholder, Upload};
+use crate::outbox::{self, staged_key, OutboxAction};
+use crate::placeholders;
+use crate::routes::conditional::{json_with_validators, urls_modified};
+use crate::models::{page_size, split_page, Media, MediaKind, PostID, PostCreate, Post, PostRow, PostUpdate, PostsPage, UserPosts, VariantFiles, Variants};
+use crate::storage::{Storage, StorageError};
 use tracing::instrument;
 
 
 // CRUD: CREATE
 #[instrument(
     name = "Creating a new post",
-    skip(new_post, pool)
+    skip(user, new_post, pool, storage, media)
     fields(
-        username = %new_post.username
+        username = %user.username,
+        media_count = new_post.media.len()
     )
 )]
-pub async fn upload_post(new_post: Multipart<PostCreate>, pool: web::Data<PgPool>) -> impl Responder {
-    let file_extension = match new_post.img_file.file_type().as_str() {
-        "image/png" => "png",
-        "image/jpeg" => "jpg",
-        _ => return HttpResponse::UnsupportedMediaType().finish()
+pub async fn upload_post(
+    user: AuthenticatedUser,
+    new_post: Validated<Form<PostCreate>>,
+    pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
+    media: web::Data<MediaSettings>
+) -> Result<HttpResponse, ApiError> {
+    let id = create_post(&pool, storage.get_ref(), media.into_inner(), &user.username, &new_post).await?;
+
+    Ok(HttpResponse::Ok().json(id))
+}
+
+/// Processes, stores and records the media of `new_post`, shared by every way of posting.
+pub(crate) async fn create_post(
+    pool: &PgPool,
+    storage: &dyn Storage,
+    media: Arc<MediaSettings>,
+    username: &str,
+    new_post: &PostCreate
+) -> Result<PostID, ApiError> {
+    // Content posted before is neither processed nor stored again
+    let hashes: Vec<String> = new_post.media.iter().map(|item| item.img_file.sha256.clone()).collect();
+    let known = known_blobs(pool, &hashes).await?;
+    if !known.is_empty() {
+        tracing::info!("Post reuses {} stored file(s)", known.len());
+    }
+
+    // Check every image before anything is stored
+    let mut processed: Vec<(&FormFile, Upload)> = Vec::new();
+    for item in &new_post.media {
+        let file = &item.img_file;
+        if known.contains(&file.sha256) || processed.iter().any(|(seen, _)| seen.sha256 == file.sha256) {
+            continue
+        }
+        processed.push((file, process_file(file, media.clone()).await?));
+    }
+
+    // Everything is written to staging first and only promoted once the post is committed,
+    // a failure on the way leaves nothing behind that a post could point to
+    let upload_id = Uuid::new_v4();
+    let mut blobs = Vec::with_capacity(processed.len());
+    for (file, upload) in processed {
+        let mut staged = Vec::new();
+        match store_media(storage, upload_id, file, &upload, &mut staged).await {
+            Ok((img_url, variants)) => blobs.push(NewBlob {
+                sha256: &file.sha256,
+                upload,
+                img_url,
+                variants,
+                staged
+            }),
+            Err(e) => {
+                staged.extend(blobs.into_iter().flat_map(|blob| blob.staged));
+                discard_staged(storage, &staged).await;
+                return Err(e.into())
+            }
+        }
+    }
+
+    let placeholder = cover_placeholder(pool, storage, new_post, &blobs).await;
+
+    let (id, promoted) = match insert_post(pool, username, new_post, &blobs, placeholder.as_ref()).await {
+        Ok(inserted) => inserted,
+        Err(e) => {
+            let staged: Vec<String> = blobs.into_iter().flat_map(|blob| blob.staged).collect();
+            discard_staged(storage, &staged).await;
+            return Err(e)
+        }
     };
 
-    let file_path = format!("./files/{}.{}", Uuid::new_v4(), file_extension);
+    // A concurrent upload of the same content may have stored it first
+    let (promote, redundant): (Vec<String>, Vec<String>) = blobs.into_iter()
+        .flat_map(|blob| blob.staged)
+        .partition(|key| promoted.contains(key));
+    outbox::process_now(pool, storage, OutboxAction::Promote, &promote).await;
+    discard_staged(storage, &redundant).await;
+
+    Ok(id)
+}
 
-    if save_file(&new_post.img_file, &file_path).await.is_err() {
-        return HttpResponse::InternalServerError().finish()
+/// Placeholder of the first media of `new_post`, computed again when that was posted before.
+/// Best effort, posts without one are filled in by the `backfill-placeholders` command.
+async fn cover_placeholder(
+    pool: &PgPool,
+    storage: &dyn Storage,
+    new_post: &PostCreate,
+    blobs: &[NewBlob<'_>]
+) -> Option<Placeholder> {
+    let cover = &new_post.media.first()?.img_file.sha256;
+    if let Some(blob) = blobs.iter().find(|blob| blob.sha256 == cover) {
+        return Some(blob.upload.placeholder().clone())
     }
 
-    match insert_post(&pool, &new_post, &file_path).await {
-        Ok(id) => HttpResponse::Ok().body(
-            serde_json::to_string(&id).unwrap()
-        ),
-        Err(_) => HttpResponse::InternalServerError().finish()
+    let stored = sqlx::query!(
+        r#"
+        SELECT kind as "kind: MediaKind", img_url, variants as "variants: Json<Variants>"
+        FROM blobs WHERE sha256 = $1
+        "#,
+        cover
+    )
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })
+        .ok()??;
+    placeholders::of_stored(storage, stored.kind, &stored.img_url, &stored.variants).await
+        .map_err(|e| tracing::warn!("Posting without a placeholder: {:?}", e))
+        .ok()
+}
+
+/// An upload whose content is not stored yet.
+struct NewBlob<'a> {
+    sha256: &'a str,
+    upload: Upload,
+    img_url: String,
+    variants: Variants,
+    /// Staged copies of the original and its variants.
+    staged: Vec<String>
+}
+
+/// The hashes among `hashes` of content already stored.
+async fn known_blobs(pool: &PgPool, hashes: &[String]) -> Result<HashSet<String>, sqlx::Error> {
+    let known = sqlx::query_scalar!(
+        r#"SELECT sha256 as "sha256!" FROM blobs WHERE sha256 = ANY($1)"#,
+        hashes
+    )
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
+
+    Ok(known.into_iter().collect())
+}
+
+/// Decoding and resizing are CPU bound, keep them off the async workers.
+#[instrument(
+    name = "Processing the uploaded file",
+    skip(file, media),
+    fields(
+        declared_type = %file.content_type,
+        size = file.size,
+        sha256 = %file.sha256
+    )
+)]
+async fn process_file(file: &FormFile, media: Arc<MediaSettings>) -> Result<Upload, ApiError> {
+    let path = file.path.to_path_buf();
+    let size = file.size;
+    let declared_type = file.content_type.clone();
+
+    web::block(move || media::process_upload(&path, size, &declared_type, &media))
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))?
+        .map_err(|e| {
+            match e {
+                MediaError::Encode(_) | MediaError::Io(_) => tracing::error!("Failed to process upload: {}", e),
+                _ => tracing::info!("Rejected upload: {}", e)
+            }
+            e.into()
+        })
+}
+
+/// Stages the original and its variants under `upload_id`, returns the key of the original.
+///
+/// Keys are named after the hash of the uploaded bytes, the stored original is re-encoded.
+/// Every key staged so far is pushed to `staged`, also when a later write fails.
+async fn store_media(
+    storage: &dyn Storage,
+    upload_id: Uuid,
+    file: &FormFile,
+    upload: &Upload,
+    staged: &mut Vec<String>
+) -> Result<(String, Variants), StorageError> {
+    let key = format!("{}.{}", file.sha256, upload.extension());
+
+    let original = staged_key(upload_id, &key);
+    match upload {
+        Upload::Image(image) => save_file(storage, &image.original, &original, upload.content_type()).await?,
+        // Videos are kept as sent, straight from the spooled upload
+        Upload::Video(_) => {
+            storage.put_file(&original, &file.path, upload.content_type()).await
+                .map_err(|e| {
+                    tracing::error!("Unable to save file {} : {:?}", original, e);
+                    e
+                })?
+        }
+    }
+    staged.push(original);
+
+    let mut variants = Variants::new();
+    for variant in upload.variants() {
+        let files = VariantFiles {
+            webp: format!("{}/{}.webp", file.sha256, variant.size),
+            jpeg: format!("{}/{}.jpg", file.sha256, variant.size)
+        };
+        for (data, key, content_type) in [(&variant.webp, &files.webp, "image/webp"), (&variant.jpeg, &files.jpeg, "image/jpeg")] {
+            let copy = staged_key(upload_id, key);
+            save_file(storage, data, &copy, content_type).await?;
+            staged.push(copy);
+        }
+        variants.insert(variant.size, files);
     }
+
+    Ok((key, variants))
 }
 
 #[instrument(
-    name = "Saving the file in fs",
-    skip(file, path),
+    name = "Saving the file in storage",
+    skip(storage, data, key, content_type),
     fields(
-    file_path = %path
+    file_key = %key
     )
 )]
-async fn save_file(file: &File, path: &str) -> std::io::Result<()> {
+async fn save_file(storage: &dyn Storage, data: &[u8], key: &str, content_type: &str) -> Result<(), StorageError> {
 
-    tokio::fs::write(path, file.data()).await
+    storage.put(key, data, content_type).await
         .map_err(|e| {
-            tracing::error!("Unable to save file {} : {:?}", path, e);
+            tracing::error!("Unable to save file {} : {:?}", key, e);
             e
         })?;
 
     Ok(())
 }
 
+/// Best effort, whatever is left over is swept by the reaper.
+async fn discard_staged(storage: &dyn Storage, keys: &[String]) {
+    for key in keys {
+        if let Err(e) = storage.delete(key).await {
+            tracing::warn!("Unable to discard staged file {}: {:?}", key, e);
+        }
+    }
+}
+
+/// Records `blobs` and points the post at them, returns the staged keys that are to be
+/// promoted. Their promotion is queued in the same transaction as the post.
 #[instrument(
     name = "Inserting the post to the database",
-    skip(pool, new_post, img_url)
+    skip(pool, username, new_post, blobs, placeholder)
 )]
 async fn insert_post(
     pool: &PgPool,
+    username: &str,
     new_post: &PostCreate,
-    img_url: &str
-) -> Result<PostID, sqlx::Error> {
+    blobs: &[NewBlob<'_>],
+    placeholder: Option<&Placeholder>
+) -> Result<(PostID, HashSet<String>), ApiError> {
 
     let id = Uuid::new_v4();
+    let mut transaction = pool.begin()
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to begin transaction {:?}", e);
+            e
+        })?;
+
     sqlx::query!(
         r#"
-        INSERT INTO posts (id, username, img_url, caption, likes, created_at)
-        VALUES ($1, $2, $3, $4, DEFAULT, DEFAULT)
+        INSERT INTO posts (id, username, caption, likes, created_at, blurhash, dominant_color)
+        VALUES ($1, $2, $3, DEFAULT, DEFAULT, $4, $5)
         "#,
         id,
-        &new_post.username,
-        &img_url,
+        username,
         new_post.caption.as_ref(),
+        placeholder.map(|placeholder| &placeholder.blurhash),
+        placeholder.map(|placeholder| &placeholder.dominant_color)
+    )
+        .execute(&mut transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
+
+    let mut promoted = HashSet::new();
+    let mut keys = Vec::new();
+    for blob in blobs {
+        let (width, height) = blob.upload.dimensions();
+        let (kind, duration_ms, codec) = match &blob.upload {
+            Upload::Image(_) => (MediaKind::Image, None, None),
+            Upload::Video(video) =>
+                (MediaKind::Video, Some(video.info.duration_ms()?), Some(video.info.codec))
+        };
+        // References are counted below, with those to blobs stored before
+        let inserted = sqlx::query!(
+            r#"
+            INSERT INTO blobs (id, sha256, img_url, variants, kind, width, height, duration_ms, codec)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            ON CONFLICT DO NOTHING
+            "#,
+            Uuid::new_v4(),
+            blob.sha256,
+            blob.img_url,
+            Json(&blob.variants) as _,
+            kind as _,
+            width as i32,
+            height as i32,
+            duration_ms,
+            codec
+        )
+            .execute(&mut transaction)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to execute query {:?}", e);
+                e
+            })?
+            .rows_affected();
+
+        if inserted > 0 {
+            promoted.extend(blob.staged.iter().cloned());
+            keys.extend(blob_keys(&blob.img_url, &blob.variants));
+        }
+    }
+
+    // The content may come back before the files of its previous life are deleted
+    sqlx::query!(
+        r#"DELETE FROM storage_outbox WHERE action = 'delete' AND key = ANY($1)"#,
+        &keys[..]
     )
-        .execute(pool)
+        .execute(&mut transaction)
         .await
         .map_err(|e| {
             tracing::error!("Failed to execute query {:?}", e);
             e
         })?;
 
-    Ok(PostID {id})
+    for (position, item) in new_post.media.iter().enumerate() {
+        let blob_id = sqlx::query_scalar!(
+            r#"UPDATE blobs SET ref_count = ref_count + 1 WHERE sha256 = $1 RETURNING id"#,
+            item.img_file.sha256
+        )
+            .fetch_optional(&mut transaction)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to execute query {:?}", e);
+                e
+            })?
+            // Only when the last post carrying it was deleted since the upload started
+            .ok_or_else(|| ApiError::ServiceUnavailable("a file of the post was removed while posting, try again".into()))?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO post_media (id, post_id, position, blob_id, alt_text, repost_of)
+            VALUES ($1, $2, $3, $4, $5, (
+                SELECT post_media.post_id
+                FROM post_media JOIN posts ON posts.id = post_media.post_id
+                WHERE post_media.blob_id = $4 AND post_media.post_id <> $2
+                ORDER BY posts.created_at
+                LIMIT 1
+            ))
+            "#,
+            Uuid::new_v4(),
+            id,
+            position as i16,
+            blob_id,
+            item.alt_text.as_ref()
+        )
+            .execute(&mut transaction)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to execute query {:?}", e);
+                e
+            })?;
+    }
+
+    let promote: Vec<String> = promoted.iter().cloned().collect();
+    outbox::enqueue(&mut transaction, OutboxAction::Promote, &promote).await?;
+
+    transaction.commit()
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to commit transaction {:?}", e);
+            e
+        })?;
+
+    Ok((PostID {id}, promoted))
 }
 
+/// Storage keys of a blob and its variants.
+fn blob_keys(img_url: &str, variants: &Variants) -> Vec<String> {
+    let mut keys = vec![img_url.to_string()];
+    for files in variants.values() {
+        keys.push(files.webp.clone());
+        keys.push(files.jpeg.clone());
+    }
+    keys
+}
 
+/// Loads the media of `rows` in one query and puts each post together, keeping the order of `rows`.
+pub(crate) async fn with_media(pool: &PgPool, rows: Vec<PostRow>) -> Result<Vec<Post>, sqlx::Error> {
+    let ids: Vec<Uuid> = rows.iter().map(|r| r.id).collect();
 
-// CRUD: DELETE
-#[instrument(
-    name = "Deleting the post",
-    skip(pool),
-    fields(
-        post_id = %post.id
+    let media = sqlx::query_as!(
+        Media,
+        r#"
+        SELECT post_media.post_id as "post_id!", blobs.id as "id!", blobs.kind as "kind!: MediaKind", blobs.img_url as "img_url!",
+            post_media.alt_text, blobs.variants as "variants!: Json<Variants>", blobs.width, blobs.height, blobs.duration_ms,
+            blobs.codec, post_media.repost_of, NULL::text as resize_url
+        FROM post_media JOIN blobs ON blobs.id = post_media.blob_id
+        WHERE post_media.post_id = ANY($1)
+        ORDER BY post_media.post_id, post_media.position
+        "#,
+        &ids[..]
     )
-)]
-pub async fn delete_post(post: web::Json<PostID>, pool: web::Data<PgPool>) -> impl Responder {
-
-    let file_name = match del_post(&post, &pool).await {
-        Ok(f) => f,
-        Err(_) => return HttpResponse::InternalServerError().finish()
-    };
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
 
-    if del_file(file_name).await.is_err() {
-        // don't do anything
-        // return HttpResponse::InternalServerError().finish();
+    let mut by_post: HashMap<Uuid, Vec<Media>> = HashMap::new();
+    for item in media {
+        by_post.entry(item.post_id).or_default().push(item);
     }
 
-    HttpResponse::Ok().finish()
+    Ok(rows.into_iter()
+        .map(|row| {
+            let media = by_post.remove(&row.id).unwrap_or_default();
+            Post::new(row, media)
+        })
+        .collect())
 }
 
+
 #[instrument(
-    name = "Removing file from fs",
-    skip(file_name),
-    fields(
-        file_path = %file_name
-    )
+    name = "Fetching post owner from database",
+    skip(pool)
 )]
-async fn del_file(file_name: String) -> std::io::Result<()> {
-    tokio::fs::remove_file(&file_name).await
+pub(crate) async fn fetch_post_owner(pool: &PgPool, post_id: Uuid) -> Result<Option<String>, sqlx::Error> {
+    let rec = sqlx::query!(
+        r#"SELECT username FROM posts WHERE id = $1"#,
+        post_id
+    )
+        .fetch_optional(pool)
+        .await
         .map_err(|e| {
-            tracing::error!("Unable to delete file {}: {:?}", file_name, e);
+            tracing::error!("Failed to execute query {:?}", e);
             e
         })?;
-    Ok(())
+
+    Ok(rec.map(|r| r.username))
+}
+
+/// Answers 404 for a missing post and 403 when `user` does not own it.
+async fn ensure_owner(pool: &PgPool, post_id: Uuid, user: &AuthenticatedUser) -> Result<(), ApiError> {
+    match fetch_post_owner(pool, post_id).await? {
+        Some(owner) if owner == user.username => Ok(()),
+        Some(_) => Err(ApiError::Forbidden("post belongs to another user".into())),
+        None => Err(ApiError::NotFound("post not found".into()))
+    }
+}
+
+
+// CRUD: DELETE
+#[instrument(
+    name = "Deleting the post",
+    skip(user, pool, storage),
+    fields(
+        post_id = %post.id,
+        username = %user.username
+    )
+)]
+pub async fn delete_post(
+    user: AuthenticatedUser,
+    post: web::Json<PostID>,
+    pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>
+) -> Result<HttpResponse, ApiError> {
+
+    ensure_owner(&pool, post.id, &user).await?;
+
+    // The deletions are queued with the post, the reaper retries whatever fails here
+    let file_names = del_post(&post, &user.username, &pool).await?;
+    outbox::process_now(&pool, storage.get_ref(), OutboxAction::Delete, &file_names).await;
+
+    Ok(HttpResponse::Ok().finish())
 }
 
+/// Returns the storage keys of the blobs the post was the last to reference, already queued for deletion.
 #[instrument(
     name = "Deleting post from database",
-    skip(pool),
+    skip(pool, username),
     fields(
         post_id = %post_id.id
     )
 )]
-async fn del_post(post_id: &PostID, pool: &PgPool) -> Result<String, sqlx::Error>
+async fn del_post(post_id: &PostID, username: &str, pool: &PgPool) -> Result<Vec<String>, sqlx::Error>
 {
+    let mut transaction = pool.begin()
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to begin transaction {:?}", e);
+            e
+        })?;
 
-    let rec = sqlx::query!(
-        r#"DELETE FROM posts WHERE id = $1 RETURNING img_url"#,
-        post_id.id
+    // Media, likes and comments go along with the post through `ON DELETE CASCADE`.
+    // The select still sees the media rows, it runs on the snapshot from before the delete
+    let blob_ids = sqlx::query_scalar!(
+        r#"
+        WITH deleted AS (
+            DELETE FROM posts WHERE id = $1 AND username = $2
+            RETURNING id
+        )
+        SELECT blob_id as "blob_id!"
+        FROM post_media
+        WHERE post_id IN (SELECT id FROM deleted)
+        "#,
+        post_id.id,
+        username
     )
-        .fetch_one(pool)
+        .fetch_all(&mut transaction)
         .await
         .map_err(|e| {
             tracing::error!("Failed to execute query {:?}", e);
             e
         })?;
 
-    Ok(format!("{}", rec.img_url))
+    // A carousel can carry the same blob more than once
+    sqlx::query!(
+        r#"
+        UPDATE blobs SET ref_count = blobs.ref_count - released.count::integer
+        FROM (SELECT id, count(*) AS count FROM unnest($1::uuid[]) AS id GROUP BY id) released
+        WHERE blobs.id = released.id
+        "#,
+        &blob_ids[..]
+    )
+        .execute(&mut transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
+
+    // Blobs no other post references go, their files with them
+    let recs = sqlx::query!(
+        r#"
+        DELETE FROM blobs WHERE id = ANY($1) AND ref_count = 0
+        RETURNING img_url, variants as "variants: Json<Variants>"
+        "#,
+        &blob_ids[..]
+    )
+        .fetch_all(&mut transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
+
+    let file_names: Vec<String> = recs.iter()
+        .flat_map(|rec| blob_keys(&rec.img_url, &rec.variants))
+        .collect();
+
+    outbox::enqueue(&mut transaction, OutboxAction::Delete, &file_names).await?;
+
+    transaction.commit()
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to commit transaction {:?}", e);
+            e
+        })?;
+
+    Ok(file_names)
 }
 
 
 #[instrument(
     name = "Fetching post from database",
-    skip(pool, post_id),
+    skip(req, pool, storage, file_urls, post_id),
     fields(
     post_id = %post_id.id
     )
 )]
 pub async fn get_post(
+    req: HttpRequest,
     post_id: web::Json<PostID>,
-    pool: web::Data<PgPool>
-) -> impl Responder {
+    pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
+    file_urls: web::Data<FileUrlSettings>
+) -> Result<HttpResponse, ApiError> {
 
-    let post = sqlx::query_as!(
-        Post,
-        r#"SELECT * FROM posts WHERE id = $1"#,
-        post_id.id
-    )
-        .fetch_one(pool.as_ref())
-        .await;
-
-    match post {
-        Ok(p) => {
-            HttpResponse::Ok().body(
-                serde_json::to_string(&p).unwrap()
-            )
-        },
-        Err(e) => {
-            tracing::error!("Failed to execute query {:?}", e);
-            HttpResponse::InternalServerError().finish()
-        }
-    }
+    let post = fetch_post(&pool, post_id.id).await?;
+
+    let modified = urls_modified(post.updated_at, &file_urls);
+    json_with_validators(&req, &post.with_public_url(storage.get_ref(), &file_urls).await?, Some(modified))
 }
 
 pub async fn get_single_post(
+    req: HttpRequest,
     path: web::Path<(String,)>,
-    pool: web::Data<PgPool>
-) -> impl Responder {
+    pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
+    file_urls: web::Data<FileUrlSettings>
+) -> Result<HttpResponse, ApiError> {
     let id_str = path.into_inner().0;
-    let id: Uuid = match Uuid::from_str(id_str.as_str()) {
-        Ok(id) => id,
-        Err(e) => {
+    let id: Uuid = id_str.parse()
+        .map_err(|e| {
             tracing::error!("Failed to parse path {} to uuid: {:?}", id_str, e);
-            return HttpResponse::BadRequest().finish();
-        }
-    };
+            ApiError::BadRequest(format!("{} is not a valid post id", id_str))
+        })?;
 
-    let post = sqlx::query_as!(
-        Post,
-        r#"SELECT * FROM posts WHERE id = $1"#,
+    let post = fetch_post(&pool, id).await?;
+
+    let modified = urls_modified(post.updated_at, &file_urls);
+    json_with_validators(&req, &post.with_public_url(storage.get_ref(), &file_urls).await?, Some(modified))
+}
+
+/// `RowNotFound` turns into a 404 through `ApiError`.
+async fn fetch_post(pool: &PgPool, id: Uuid) -> Result<Post, sqlx::Error> {
+    let row = sqlx::query_as!(
+        PostRow,
+        r#"
+        SELECT id, username, caption, likes, created_at, updated_at, blurhash, dominant_color
+        FROM posts WHERE id = $1
+        "#,
         id
     )
-        .fetch_one(pool.as_ref())
-        .await;
-
-    match post {
-        Ok(p) => {
-            HttpResponse::Ok().body(
-                serde_json::to_string(&p).unwrap()
-            )
-        },
-        Err(e) => {
-            tracing::error!("Failed to execute query {:?}", e);
-            HttpResponse::InternalServerError().finish()
-        }
-    }
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            if !matches!(e, sqlx::Error::RowNotFound) {
+                tracing::error!("Failed to execute query {:?}", e);
+            }
+            e
+        })?;
 
+    let mut posts = with_media(pool, vec![row]).await?;
+    Ok(posts.remove(0))
 }
 
 pub async fn get_use_posts(
+    req: HttpRequest,
     path: web::Path<(String,)>,
-    pool: web::Data<PgPool>
-) -> impl Responder {
+    page: Validated<web::Query<PostsPage>>,
+    pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
+    file_urls: web::Data<FileUrlSettings>
+) -> Result<HttpResponse, ApiError> {
 
     let username = path.into_inner().0;
 
-    let query_result = sqlx::query!(
+    let limit = page_size(page.limit);
+
+    let rows = sqlx::query_as!(
+        PostRow,
         r#"
-        SELECT * FROM posts
+        SELECT id, username, caption, likes, created_at, updated_at, blurhash, dominant_color
+        FROM posts
         WHERE username = $1
-        ORDER BY created_at
+        AND ($2::timestamp IS NULL OR (created_at, id) < ($2, $3::uuid))
+        ORDER BY created_at DESC, id DESC
+        LIMIT $4
         "#,
-        username
+        username,
+        page.cursor.map(|c| c.created_at),
+        page.cursor.map(|c| c.id),
+        limit + 1
     )
         .fetch_all(pool.as_ref())
-        .await;
-
-    let records = match query_result {
-        Ok(r) => r,
-        Err(e) => {
+        .await
+        .map_err(|e| {
             tracing::error!("Failed to execute query {:?}", e);
-            return HttpResponse::InternalServerError().finish()
-        }
-    };
+            e
+        })?;
 
-    let posts: Vec<Post> = records.into_iter()
-        .map(|r| {
-            Post {
-                id: r.id,
-                username: r.username,
-                img_url: r.img_url,
-                caption: r.caption,
-                likes: r.likes,
-                created_at: r.created_at
-            }
-        }).collect();
+    let (rows, next_cursor) = split_page(rows, limit);
+    let posts = with_media(pool.as_ref(), rows).await?;
 
     let user_posts = UserPosts {
-        posts
+        posts: try_join_all(posts.into_iter().map(|p| p.with_public_url(storage.get_ref(), &file_urls))).await?,
+        next_cursor
     };
 
-    HttpResponse::Ok()
-        .json(user_posts)
+    json_with_validators(&req, &user_posts, None)
 
 }
 
 #[instrument(
     name = "Updating post in the database",
-    skip(pool, update),
+    skip(user, pool, update),
     fields(
-        post_id = %update.id
+        post_id = %update.id,
+        username = %user.username
     )
 )]
 pub async fn update_post(
-    update: web::Json<PostUpdate>,
+    user: AuthenticatedUser,
+    update: Validated<web::Json<PostUpdate>>,
     pool: web::Data<PgPool>
-) -> impl Responder {
+) -> Result<HttpResponse, ApiError> {
 
-    match sqlx::query!(
+    ensure_owner(&pool, update.id, &user).await?;
+
+    sqlx::query!(
         r#"
         UPDATE posts
-        SET caption = $1
-        WHERE id = $2
+        SET caption = $1, updated_at = current_timestamp
+        WHERE id = $2 AND username = $3
         "#,
         update.caption,
-        update.id
+        update.id,
+        &user.username
     )
         .execute(pool.as_ref())
-        .await {
-        Ok(_) => HttpResponse::Ok().finish(),
-        Err(_) => HttpResponse::InternalServerError().finish()
-    }
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?;
+
+    Ok(HttpResponse::Ok().finish())
 }
\ No newline at end of file
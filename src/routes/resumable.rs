@@ -0,0 +1,529 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::time::SystemTime;
+
+use actix_web::http::header::{self, HttpDate};
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder, web};
+use chrono::NaiveDateTime;
+use futures_util::StreamExt;
+use sqlx::PgPool;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tracing::instrument;
+use uuid::Uuid;
+use crate::auth::AuthenticatedUser;
+use crate::configuration::{MediaSettings, ResumableSettings};
+use crate::error::ApiError;
+use crate::extract::{FormFile, Validated};
+use crate::models::{MediaCreate, PostCreate, PostFromUploads, UploadSession};
+use crate::resumable;
+use crate::routes::post::create_post;
+use crate::storage::Storage;
+
+// Resumable uploads follow the core of the tus protocol (https://tus.io/protocols/resumable-upload):
+// `POST` opens a session, `PATCH` appends bytes at `Upload-Offset`, `HEAD` tells how far it got
+const TUS_RESUMABLE: &str = "Tus-Resumable";
+const TUS_VERSION: &str = "1.0.0";
+const UPLOAD_LENGTH: &str = "Upload-Length";
+const UPLOAD_OFFSET: &str = "Upload-Offset";
+const UPLOAD_METADATA: &str = "Upload-Metadata";
+const UPLOAD_EXPIRES: &str = "Upload-Expires";
+const CHUNK_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+
+/// Opens a session for `Upload-Length` bytes. `Upload-Metadata` has to name the `filetype`,
+/// and may give a `filename`.
+#[instrument(
+    name = "Creating an upload session",
+    skip(user, req, pool, media, settings),
+    fields(
+        username = %user.username
+    )
+)]
+pub async fn create_upload(
+    user: AuthenticatedUser,
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    media: web::Data<MediaSettings>,
+    settings: web::Data<ResumableSettings>
+) -> Result<HttpResponse, ApiError> {
+    let length = number_header(&req, UPLOAD_LENGTH)?
+        .ok_or_else(|| ApiError::BadRequest(format!("{} is required", UPLOAD_LENGTH)))?;
+    if length == 0 {
+        return Err(ApiError::BadRequest("empty uploads are not accepted".into()))
+    }
+    let max = media.max_upload_bytes() as u64;
+    if length > max {
+        return Err(ApiError::PayloadTooLarge(format!("uploads are limited to {} bytes", max)))
+    }
+
+    let mut metadata = parse_metadata(&req)?;
+    let content_type = metadata.remove("filetype")
+        .ok_or_else(|| ApiError::BadRequest(format!("{} has to name the filetype", UPLOAD_METADATA)))?;
+    let file_name = metadata.remove("filename").unwrap_or_else(|| "upload".into());
+
+    let id = Uuid::new_v4();
+    let create_failed = |e: std::io::Error| ApiError::Internal(format!("failed to create the upload file: {}", e));
+    tokio::fs::create_dir_all(&settings.directory).await.map_err(create_failed)?;
+    tokio::fs::File::create(resumable::session_path(&settings, id)).await.map_err(create_failed)?;
+
+    let session = sqlx::query_as!(
+        UploadSession,
+        r#"
+        INSERT INTO upload_sessions (id, username, file_name, content_type, upload_length, expires_at)
+        VALUES ($1, $2, $3, $4, $5, current_timestamp + $6 * interval '1 second')
+        RETURNING id, file_name, content_type, upload_length, upload_offset, expires_at
+        "#,
+        id,
+        &user.username,
+        file_name,
+        content_type,
+        length as i64,
+        settings.expiry_seconds as f64
+    )
+        .fetch_one(pool.as_ref())
+        .await;
+    let session = match session {
+        Ok(session) => session,
+        Err(e) => {
+            tracing::error!("Failed to execute query {:?}", e);
+            resumable::remove_session_files(&settings, &[id]).await;
+            return Err(e.into())
+        }
+    };
+
+    Ok(tus_response(HttpResponse::Created(), &session)
+        .insert_header((header::LOCATION, format!("{}/{}", req.path().trim_end_matches('/'), id)))
+        .json(&session))
+}
+
+/// Tells how many bytes of the session were received, so a client knows where to resume.
+pub async fn upload_status(
+    user: AuthenticatedUser,
+    path: web::Path<(Uuid,)>,
+    pool: web::Data<PgPool>
+) -> Result<HttpResponse, ApiError> {
+    let session = fetch_session(&pool, path.into_inner().0, &user.username).await?;
+
+    Ok(tus_response(HttpResponse::Ok(), &session)
+        .insert_header((UPLOAD_LENGTH, session.upload_length.to_string()))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .finish())
+}
+
+/// Appends the body at `Upload-Offset`, which has to be where the session is at.
+///
+/// Whatever arrives before the connection drops is kept, the client resumes from there.
+#[instrument(
+    name = "Appending to an upload session",
+    skip(user, req, body, pool, settings),
+    fields(
+        upload_id = %path.0,
+        username = %user.username
+    )
+)]
+pub async fn append_chunk(
+    user: AuthenticatedUser,
+    req: HttpRequest,
+    path: web::Path<(Uuid,)>,
+    mut body: web::Payload,
+    pool: web::Data<PgPool>,
+    settings: web::Data<ResumableSettings>
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner().0;
+
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    if content_type != Some(CHUNK_CONTENT_TYPE) {
+        return Err(ApiError::UnsupportedMediaType(format!("chunks are sent as {}", CHUNK_CONTENT_TYPE)))
+    }
+    let offset = number_header(&req, UPLOAD_OFFSET)?
+        .ok_or_else(|| ApiError::BadRequest(format!("{} is required", UPLOAD_OFFSET)))?;
+
+    let (claim, mut sessions) = resumable::claim(&pool, &settings, &[id], &user.username).await?;
+    let appended = append_claimed(&req, &mut body, &pool, &settings, &sessions.remove(0), offset, claim).await;
+    if appended.is_err() {
+        resumable::release(&pool, claim).await;
+    }
+    appended
+}
+
+/// `append_chunk` once the session is claimed, the claim goes with the new offset.
+async fn append_claimed(
+    req: &HttpRequest,
+    body: &mut web::Payload,
+    pool: &PgPool,
+    settings: &ResumableSettings,
+    session: &UploadSession,
+    offset: u64,
+    claim: Uuid
+) -> Result<HttpResponse, ApiError> {
+    if offset != session.upload_offset as u64 {
+        return Err(ApiError::Conflict(format!("the upload is at offset {}", session.upload_offset)))
+    }
+    // Refuse a chunk announcing it goes past the end before any of it is written
+    let announced = number_header(req, header::CONTENT_LENGTH.as_str())?;
+    if announced.is_some_and(|announced| offset + announced > session.upload_length as u64) {
+        return Err(ApiError::PayloadTooLarge(format!("the upload is {} bytes long", session.upload_length)))
+    }
+
+    let written = write_chunk(settings, session, body).await;
+    let received = match &written {
+        Ok(received) | Err((received, _)) => *received
+    };
+
+    let expires_at = sqlx::query_scalar!(
+        r#"
+        UPDATE upload_sessions
+        SET upload_offset = $2, expires_at = current_timestamp + $3 * interval '1 second',
+            claimed_by = NULL, claimed_until = NULL
+        WHERE id = $1 AND claimed_by = $4
+        RETURNING expires_at
+        "#,
+        session.id,
+        received as i64,
+        settings.expiry_seconds as f64,
+        claim
+    )
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?
+        // Expired, or the claim ran out, while the chunk was coming in
+        .ok_or_else(|| ApiError::Conflict("the upload expired or was taken over during the chunk".into()))?;
+
+    if let Err((_, e)) = written {
+        return Err(e)
+    }
+
+    Ok(HttpResponse::NoContent()
+        .insert_header((TUS_RESUMABLE, TUS_VERSION))
+        .insert_header((UPLOAD_OFFSET, received.to_string()))
+        .insert_header((UPLOAD_EXPIRES, http_date(expires_at)))
+        .finish())
+}
+
+/// Returns the offset reached, also when the chunk could not be written whole.
+async fn write_chunk(
+    settings: &ResumableSettings,
+    session: &UploadSession,
+    body: &mut web::Payload
+) -> Result<u64, (u64, ApiError)> {
+    let offset = session.upload_offset as u64;
+    let length = session.upload_length as u64;
+    let write_failed = |e: std::io::Error| ApiError::Internal(format!("failed to write the upload file: {}", e));
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(resumable::session_path(settings, session.id))
+        .await
+        .map_err(|e| (offset, write_failed(e)))?;
+    // Drop what an interrupted chunk wrote past the recorded offset
+    file.set_len(offset).await.map_err(|e| (offset, write_failed(e)))?;
+    file.seek(SeekFrom::Start(offset)).await.map_err(|e| (offset, write_failed(e)))?;
+
+    let mut received = offset;
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => return Err((received, ApiError::BadRequest(format!("the chunk was cut short: {}", e))))
+        };
+        if received + chunk.len() as u64 > length {
+            return Err((received, ApiError::PayloadTooLarge(format!("the upload is {} bytes long", length))))
+        }
+        file.write_all(&chunk).await.map_err(|e| (received, write_failed(e)))?;
+        received += chunk.len() as u64;
+    }
+    file.flush().await.map_err(|e| (received, write_failed(e)))?;
+
+    Ok(received)
+}
+
+#[instrument(
+    name = "Cancelling an upload session",
+    skip(user, pool, settings),
+    fields(
+        upload_id = %path.0,
+        username = %user.username
+    )
+)]
+pub async fn cancel_upload(
+    user: AuthenticatedUser,
+    path: web::Path<(Uuid,)>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<ResumableSettings>
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner().0;
+
+    let (claim, _) = resumable::claim(&pool, &settings, &[id], &user.username).await?;
+    let removed = sqlx::query!(
+        r#"DELETE FROM upload_sessions WHERE id = $1 AND claimed_by = $2"#,
+        id,
+        claim
+    )
+        .execute(pool.as_ref())
+        .await;
+    if let Err(e) = removed {
+        tracing::error!("Failed to execute query {:?}", e);
+        resumable::release(&pool, claim).await;
+        return Err(e.into())
+    }
+
+    resumable::remove_session_files(&settings, &[id]).await;
+
+    Ok(HttpResponse::NoContent()
+        .insert_header((TUS_RESUMABLE, TUS_VERSION))
+        .finish())
+}
+
+/// Posts complete uploads, the sessions are gone once the post is created.
+///
+/// The sessions are claimed until then, so two requests can't both post them.
+#[instrument(
+    name = "Creating a post from resumable uploads",
+    skip(user, new_post, pool, storage, media, settings),
+    fields(
+        username = %user.username,
+        media_count = new_post.media.len()
+    )
+)]
+pub async fn finish_upload(
+    user: AuthenticatedUser,
+    new_post: Validated<web::Json<PostFromUploads>>,
+    pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
+    media: web::Data<MediaSettings>,
+    settings: web::Data<ResumableSettings>
+) -> Result<HttpResponse, ApiError> {
+    let new_post = new_post.into_inner().into_inner();
+    let ids: Vec<Uuid> = new_post.media.iter().map(|item| item.upload_id).collect();
+
+    let (claim, sessions) = resumable::claim(&pool, &settings, &ids, &user.username).await?;
+    let sessions: HashMap<Uuid, UploadSession> = sessions.into_iter()
+        .map(|session| (session.id, session))
+        .collect();
+    let post = match post_from_sessions(new_post, &sessions, &settings).await {
+        Ok(post) => create_post(&pool, storage.get_ref(), media.into_inner(), &user.username, &post).await,
+        Err(e) => Err(e)
+    };
+    let id = match post {
+        Ok(id) => id,
+        Err(e) => {
+            resumable::release(&pool, claim).await;
+            return Err(e)
+        }
+    };
+
+    let removed = sqlx::query!(
+        r#"DELETE FROM upload_sessions WHERE id = ANY($1) AND claimed_by = $2"#,
+        &ids[..],
+        claim
+    )
+        .execute(pool.as_ref())
+        .await;
+    match removed {
+        Ok(_) => resumable::remove_session_files(&settings, &ids).await,
+        // The post is there, the sessions expire on their own
+        Err(e) => tracing::error!("Failed to execute query {:?}", e)
+    }
+
+    Ok(HttpResponse::Ok().json(id))
+}
+
+/// The post `new_post` makes of complete, claimed `sessions`.
+async fn post_from_sessions(
+    new_post: PostFromUploads,
+    sessions: &HashMap<Uuid, UploadSession>,
+    settings: &ResumableSettings
+) -> Result<PostCreate, ApiError> {
+    let mut items = Vec::with_capacity(new_post.media.len());
+    for item in new_post.media {
+        let session = &sessions[&item.upload_id];
+        if session.upload_offset < session.upload_length {
+            return Err(ApiError::Conflict(format!(
+                "upload {} is incomplete, {} of {} bytes were received",
+                session.id, session.upload_offset, session.upload_length
+            )))
+        }
+
+        let (settings, id) = (settings.clone(), session.id);
+        let (path, sha256) = web::block(move || resumable::link_session(&settings, id))
+            .await
+            .map_err(|e| ApiError::Internal(e.to_string()))?
+            .map_err(|e| ApiError::Internal(format!("failed to read upload {}: {}", id, e)))?;
+
+        items.push(MediaCreate {
+            img_file: FormFile {
+                file_name: session.file_name.clone(),
+                content_type: session.content_type.clone(),
+                path,
+                size: session.upload_length as u64,
+                sha256
+            },
+            alt_text: item.alt_text.filter(|alt| !alt.is_empty())
+        });
+    }
+
+    Ok(PostCreate {
+        media: items,
+        caption: new_post.caption
+    })
+}
+
+/// Expired sessions and those of other users are answered with a 404 alike.
+async fn fetch_session(pool: &PgPool, id: Uuid, username: &str) -> Result<UploadSession, ApiError> {
+    sqlx::query_as!(
+        UploadSession,
+        r#"
+        SELECT id, file_name, content_type, upload_length, upload_offset, expires_at
+        FROM upload_sessions
+        WHERE id = $1 AND username = $2 AND expires_at > current_timestamp
+        "#,
+        id,
+        username
+    )
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query {:?}", e);
+            e
+        })?
+        .ok_or_else(|| ApiError::NotFound("upload not found".into()))
+}
+
+fn tus_response(mut builder: HttpResponseBuilder, session: &UploadSession) -> HttpResponseBuilder {
+    builder
+        .insert_header((TUS_RESUMABLE, TUS_VERSION))
+        .insert_header((UPLOAD_OFFSET, session.upload_offset.to_string()))
+        .insert_header((UPLOAD_EXPIRES, http_date(session.expires_at)));
+    builder
+}
+
+fn http_date(at: NaiveDateTime) -> String {
+    HttpDate::from(SystemTime::from(at.and_utc())).to_string()
+}
+
+fn number_header(req: &HttpRequest, name: &str) -> Result<Option<u64>, ApiError> {
+    req.headers().get(name)
+        .map(|value| value.to_str().ok()
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .ok_or_else(|| ApiError::BadRequest(format!("{} is not a number of bytes", name))))
+        .transpose()
+}
+
+/// `Upload-Metadata` is a comma separated list of keys, each followed by its base64 encoded value.
+fn parse_metadata(req: &HttpRequest) -> Result<HashMap<String, String>, ApiError> {
+    let malformed = || ApiError::BadRequest(format!("malformed {}", UPLOAD_METADATA));
+
+    let raw = match req.headers().get(UPLOAD_METADATA) {
+        Some(raw) => raw.to_str().map_err(|_| malformed())?,
+        None => return Ok(HashMap::new())
+    };
+
+    let mut metadata = HashMap::new();
+    for pair in raw.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let (key, value) = match pair.split_once(' ') {
+            Some((key, encoded)) => {
+                let decoded = base64::decode(encoded.trim()).map_err(|_| malformed())?;
+                (key, String::from_utf8(decoded).map_err(|_| malformed())?)
+            }
+            None => (pair, String::new())
+        };
+        metadata.insert(key.to_string(), value);
+    }
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::FromRequest;
+    use actix_web::test::TestRequest;
+    use super::*;
+
+    fn session(upload_length: i64, upload_offset: i64) -> UploadSession {
+        UploadSession {
+            id: Uuid::new_v4(),
+            file_name: "upload".into(),
+            content_type: "image/png".into(),
+            upload_length,
+            upload_offset,
+            expires_at: chrono::Utc::now().naive_utc()
+        }
+    }
+
+    fn settings(directory: &tempfile::TempDir) -> ResumableSettings {
+        ResumableSettings {
+            directory: directory.path().to_string_lossy().into_owned(),
+            expiry_seconds: 60,
+            claim_seconds: 60,
+            cleanup_interval_seconds: 60
+        }
+    }
+
+    async fn payload(chunk: &'static [u8]) -> web::Payload {
+        let (req, mut payload) = TestRequest::default().set_payload(chunk).to_http_parts();
+        web::Payload::from_request(&req, &mut payload).await.unwrap()
+    }
+
+    #[actix_web::test]
+    async fn chunks_are_written_at_the_recorded_offset() {
+        let directory = tempfile::tempdir().unwrap();
+        let settings = settings(&directory);
+        let session = session(10, 5);
+        // The tail was written by a chunk that was cut short and never recorded
+        std::fs::write(resumable::session_path(&settings, session.id), b"hellojunk").unwrap();
+
+        let received = write_chunk(&settings, &session, &mut payload(b"world").await).await.unwrap();
+        assert_eq!(received, 10);
+        assert_eq!(std::fs::read(resumable::session_path(&settings, session.id)).unwrap(), b"helloworld");
+    }
+
+    #[actix_web::test]
+    async fn chunks_past_the_length_are_refused() {
+        let directory = tempfile::tempdir().unwrap();
+        let settings = settings(&directory);
+        let session = session(8, 5);
+        std::fs::write(resumable::session_path(&settings, session.id), b"hello").unwrap();
+
+        let (received, error) = write_chunk(&settings, &session, &mut payload(b"world").await).await.unwrap_err();
+        assert_eq!(received, 5);
+        assert!(matches!(error, ApiError::PayloadTooLarge(_)));
+    }
+
+    #[actix_web::test]
+    async fn chunks_need_the_session_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let (received, error) = write_chunk(&settings(&directory), &session(5, 0), &mut payload(b"hello").await)
+            .await
+            .unwrap_err();
+        assert_eq!(received, 0);
+        assert!(matches!(error, ApiError::Internal(_)));
+    }
+
+    #[test]
+    fn offsets_are_numbers_of_bytes() {
+        let req = TestRequest::default()
+            .insert_header((UPLOAD_OFFSET, " 42 "))
+            .insert_header((UPLOAD_LENGTH, "-1"))
+            .to_http_request();
+        assert_eq!(number_header(&req, UPLOAD_OFFSET).unwrap(), Some(42));
+        assert!(matches!(number_header(&req, UPLOAD_LENGTH), Err(ApiError::BadRequest(_))));
+        assert_eq!(number_header(&req, "Upload-Missing").unwrap(), None);
+    }
+
+    #[test]
+    fn metadata_values_are_decoded() {
+        let req = TestRequest::default()
+            .insert_header((UPLOAD_METADATA, "filename cGhvdG8ucG5n, is_private,filetype aW1hZ2UvcG5n"))
+            .to_http_request();
+        let metadata = parse_metadata(&req).unwrap();
+        assert_eq!(metadata.len(), 3);
+        assert_eq!(metadata["filename"], "photo.png");
+        assert_eq!(metadata["filetype"], "image/png");
+        assert_eq!(metadata["is_private"], "");
+
+        assert!(parse_metadata(&TestRequest::default().to_http_request()).unwrap().is_empty());
+        let req = TestRequest::default().insert_header((UPLOAD_METADATA, "filename not-base64!")).to_http_request();
+        assert!(matches!(parse_metadata(&req), Err(ApiError::BadRequest(_))));
+    }
+}
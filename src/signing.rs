@@ -0,0 +1,124 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+
+
+/// Issues and checks short-lived tokens that grant access to one thing, named by their
+/// payload, without a lookup: `<payload>.<expiry as unix seconds>.<HMAC-SHA256>`.
+pub struct Signer {
+    key: Secret<String>
+}
+
+impl Signer {
+    pub fn new(key: Secret<String>) -> Self {
+        Signer { key }
+    }
+
+    pub fn sign(&self, payload: &str, expires_at: SystemTime) -> String {
+        let expires = expires_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        format!("{}.{}.{}", payload, expires, self.signature(payload, expires))
+    }
+
+    /// The payload of `token`, when it was signed by us and has not expired.
+    pub fn verify<'a>(&self, token: &'a str) -> Option<&'a str> {
+        let (message, signature) = token.rsplit_once('.')?;
+        let (payload, expires) = message.rsplit_once('.')?;
+        let expires: u64 = expires.parse().ok()?;
+        self.verify_signature(payload, expires, signature).then_some(payload)
+    }
+
+    /// The signature part of a token alone, for URLs that carry the payload and the expiry
+    /// (as unix seconds) in places of their own.
+    pub fn signature(&self, payload: &str, expires: u64) -> String {
+        let signature = self.mac(&format!("{}.{}", payload, expires)).finalize().into_bytes();
+        base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn verify_signature(&self, payload: &str, expires: u64, signature: &str) -> bool {
+        let Ok(signature) = base64::decode_config(signature, base64::URL_SAFE_NO_PAD) else {
+            return false
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.mac(&format!("{}.{}", payload, expires)).verify_slice(&signature).is_ok() && now < expires
+    }
+
+    fn mac(&self, message: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.expose_secret().as_bytes())
+            .expect("HMAC takes keys of any length");
+        mac.update(message.as_bytes());
+        mac
+    }
+}
+
+/// Expiry, as unix seconds, of URLs handed out now that must stay valid at least `period`
+/// seconds. Rounded up to a whole period, so URLs only change once per period and stay
+/// valid up to twice as long.
+pub fn url_expiry(period: u64) -> u64 {
+    url_period_start(period) + 2 * period.max(1)
+}
+
+/// When the URLs `url_expiry` gives an expiry to last changed, as unix seconds.
+pub fn url_period_start(period: u64) -> u64 {
+    let period = period.max(1);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    now / period * period
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    fn signer() -> Signer {
+        Signer::new(Secret::new("test-secret".into()))
+    }
+
+    fn in_a_minute() -> SystemTime {
+        SystemTime::now() + Duration::from_secs(60)
+    }
+
+    #[test]
+    fn tokens_carry_their_payload() {
+        let signer = signer();
+        let token = signer.sign("some.dotted.payload", in_a_minute());
+        assert_eq!(signer.verify(&token), Some("some.dotted.payload"));
+    }
+
+    #[test]
+    fn tampered_tokens_are_refused() {
+        let signer = signer();
+        let token = signer.sign("upload", in_a_minute());
+        let (message, signature) = token.rsplit_once('.').unwrap();
+        let (_, expires) = message.rsplit_once('.').unwrap();
+
+        assert_eq!(signer.verify(&token.replacen("upload", "uploaf", 1)), None);
+        let later: u64 = expires.parse::<u64>().unwrap() + 3600;
+        assert_eq!(signer.verify(&format!("upload.{}.{}", later, signature)), None);
+        assert_eq!(signer.verify(&format!("{}.{}", message, "A".repeat(signature.len()))), None);
+        assert_eq!(signer.verify(&format!("{}.not base64", message)), None);
+        assert_eq!(signer.verify("upload"), None);
+        assert_eq!(Signer::new(Secret::new("other-secret".into())).verify(&token), None);
+    }
+
+    #[test]
+    fn expired_tokens_are_refused() {
+        let signer = signer();
+        let token = signer.sign("upload", SystemTime::now() - Duration::from_secs(1));
+        assert_eq!(signer.verify(&token), None);
+    }
+
+    #[test]
+    fn signatures_cover_the_payload_and_the_expiry() {
+        let signer = signer();
+        let expires = in_a_minute().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let signature = signer.signature("key", expires);
+
+        assert!(signer.verify_signature("key", expires, &signature));
+        assert!(!signer.verify_signature("other", expires, &signature));
+        assert!(!signer.verify_signature("key", expires + 1, &signature));
+        let past = expires - 120;
+        assert!(!signer.verify_signature("key", past, &signer.signature("key", past)));
+    }
+}
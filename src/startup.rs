@@ -1,24 +1,48 @@
 use std::net::TcpListener;
+use std::sync::Arc;
 use actix_cors::Cors;
-use actix_web::dev::Server;
-use actix_web::{App, HttpServer, web};
+use actix_web::dev::{Server, Service};
+use actix_web::{App, HttpMessage, HttpServer, web};
 use sqlx::PgPool;
-use tracing_actix_web::TracingLogger;
+use tracing_actix_web::{RequestId, TracingLogger};
 use crate::auth::AuthClient;
+use crate::configuration::{DirectUploadSettings, FileUrlSettings, ImageSettings, MediaSettings, ResumableSettings};
+use crate::error::attach_request_id;
+use crate::image_cache::ImageCache;
 use crate::routes::*;
+use crate::storage::Storage;
 
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     listener: TcpListener,
     db_pool: PgPool,
-    auth_client: AuthClient
+    auth_client: AuthClient,
+    storage: Arc<dyn Storage>,
+    media: MediaSettings,
+    resumable: ResumableSettings,
+    direct_uploads: DirectUploadSettings,
+    file_urls: FileUrlSettings,
+    images: ImageSettings
 ) -> Result<Server, std::io::Error> {
     // Wrap hte connection in a smart pointer
     let db_pool = web::Data::new(db_pool);
     let auth_client = web::Data::new(auth_client);
+    let storage: web::Data<dyn Storage> = web::Data::from(storage);
+    let media = web::Data::new(media);
+    let resumable = web::Data::new(resumable);
+    let direct_uploads = web::Data::new(direct_uploads);
+    let file_urls = web::Data::new(file_urls);
+    let image_cache = web::Data::new(ImageCache::open(&images)?);
+    let images = web::Data::new(images);
 
     let server = HttpServer::new(move || {
 
         App::new()
+            .wrap_fn(|req, srv| {
+                let request_id = req.extensions().get::<RequestId>().copied();
+                let fut = srv.call(req);
+                async move { attach_request_id(fut.await, request_id) }
+            })
             .wrap(Cors::default()
                   .allow_any_origin()
                   .send_wildcard())
@@ -26,6 +50,13 @@ pub fn run(
             .configure(app_config)
             .app_data(db_pool.clone())
             .app_data(auth_client.clone())
+            .app_data(storage.clone())
+            .app_data(media.clone())
+            .app_data(resumable.clone())
+            .app_data(direct_uploads.clone())
+            .app_data(file_urls.clone())
+            .app_data(image_cache.clone())
+            .app_data(images.clone())
     })
         .listen(listener)?
         .run();
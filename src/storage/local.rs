@@ -0,0 +1,144 @@
+use std::path::{Component, Path, PathBuf};
+
+use async_trait::async_trait;
+
+use crate::signing::{url_expiry, Signer};
+use super::{Storage, StorageError, StoredObject};
+
+
+/// Stores objects as plain files below `root`, served back under `base_url` through URLs
+/// signed for `url_expiry_seconds`.
+pub struct LocalStorage {
+    root: PathBuf,
+    base_url: String,
+    signer: Signer,
+    url_expiry_seconds: u64,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf, base_url: String, signer: Signer, url_expiry_seconds: u64) -> Self {
+        LocalStorage {
+            root,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            signer,
+            url_expiry_seconds: url_expiry_seconds.max(1)
+        }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, StorageError> {
+        let relative = Path::new(key);
+        let is_plain = relative.components()
+            .all(|c| matches!(c, Component::Normal(_)));
+        if !is_plain {
+            return Err(StorageError::Backend(format!("invalid object key {}", key)));
+        }
+        Ok(self.root.join(relative))
+    }
+
+    /// Drop the directories left empty by nested keys, `remove_dir` refuses non-empty ones.
+    async fn remove_empty_parent(&self, path: &Path) {
+        let mut parent = path.parent();
+        while let Some(dir) = parent.filter(|p| *p != self.root) {
+            if tokio::fs::remove_dir(dir).await.is_err() {
+                break
+            }
+            parent = dir.parent();
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: &[u8], _content_type: &str) -> Result<(), StorageError> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&path, data).await?;
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, source: &Path, _content_type: &str) -> Result<(), StorageError> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Not `fs::copy`, which would carry over the owner-only mode of temporary files
+        let mut source = tokio::fs::File::open(source).await?;
+        let mut target = tokio::fs::File::create(&path).await?;
+        tokio::io::copy(&mut source, &mut target).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        let path = self.path(key)?;
+        Ok(tokio::fs::read(&path).await?)
+    }
+
+    async fn get_file(&self, key: &str, path: &Path) -> Result<(), StorageError> {
+        tokio::fs::copy(self.path(key)?, path).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let path = self.path(key)?;
+        tokio::fs::remove_file(&path).await?;
+        self.remove_empty_parent(&path).await;
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        let (from, to) = (self.path(from)?, self.path(to)?);
+        if let Some(parent) = to.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(&from, &to).await?;
+        self.remove_empty_parent(&from).await;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>, StorageError> {
+        let mut objects = Vec::new();
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                // Nothing was ever stored
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into())
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if metadata.is_dir() {
+                    dirs.push(entry.path());
+                    continue
+                }
+                let key = entry.path().strip_prefix(&self.root)
+                    .map(|relative| relative.components()
+                        .map(|c| c.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/"))
+                    .map_err(|e| StorageError::Backend(e.to_string()))?;
+                if key.starts_with(prefix) {
+                    objects.push(StoredObject {
+                        key,
+                        modified: metadata.modified()?
+                    });
+                }
+            }
+        }
+        Ok(objects)
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        self.path(key).ok()
+    }
+
+    async fn url(&self, key: &str) -> Result<String, StorageError> {
+        let expires = url_expiry(self.url_expiry_seconds);
+        Ok(format!(
+            "{}/{}?expires={}&signature={}",
+            self.base_url, key, expires, self.signer.signature(key, expires)
+        ))
+    }
+}
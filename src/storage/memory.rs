@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+
+use super::{Storage, StorageError, StoredObject};
+
+
+/// Keeps every object in process memory. Only meant for tests and local experiments.
+#[derive(Default)]
+pub struct MemoryStorage {
+    objects: RwLock<HashMap<String, (Vec<u8>, SystemTime)>>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn put(&self, key: &str, data: &[u8], _content_type: &str) -> Result<(), StorageError> {
+        self.objects.write()
+            .expect("memory storage lock poisoned")
+            .insert(key.to_string(), (data.to_vec(), SystemTime::now()));
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        self.objects.read()
+            .expect("memory storage lock poisoned")
+            .get(key)
+            .map(|(data, _)| data.clone())
+            .ok_or_else(|| StorageError::NotFound(key.to_string()))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.objects.write()
+            .expect("memory storage lock poisoned")
+            .remove(key)
+            .map(|_| ())
+            .ok_or_else(|| StorageError::NotFound(key.to_string()))
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        let mut objects = self.objects.write()
+            .expect("memory storage lock poisoned");
+        let object = objects.remove(from)
+            .ok_or_else(|| StorageError::NotFound(from.to_string()))?;
+        objects.insert(to.to_string(), object);
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>, StorageError> {
+        Ok(self.objects.read()
+            .expect("memory storage lock poisoned")
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, (_, modified))| StoredObject {
+                key: key.clone(),
+                modified: *modified
+            })
+            .collect())
+    }
+
+    async fn url(&self, key: &str) -> Result<String, StorageError> {
+        Ok(format!("memory://{}", key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn stores_and_returns_objects() {
+        let storage = MemoryStorage::default();
+        storage.put("a.png", b"first", "image/png").await.unwrap();
+        assert_eq!(storage.get("a.png").await.unwrap(), b"first");
+
+        storage.put("a.png", b"second", "image/png").await.unwrap();
+        assert_eq!(storage.get("a.png").await.unwrap(), b"second");
+    }
+
+    #[actix_web::test]
+    async fn missing_objects_are_not_found() {
+        let storage = MemoryStorage::default();
+        assert!(matches!(storage.get("missing").await, Err(StorageError::NotFound(_))));
+        assert!(matches!(storage.delete("missing").await, Err(StorageError::NotFound(_))));
+        assert!(matches!(storage.rename("missing", "other").await, Err(StorageError::NotFound(_))));
+    }
+
+    #[actix_web::test]
+    async fn delete_removes_the_object() {
+        let storage = MemoryStorage::default();
+        storage.put("a.png", b"data", "image/png").await.unwrap();
+        storage.delete("a.png").await.unwrap();
+        assert!(matches!(storage.get("a.png").await, Err(StorageError::NotFound(_))));
+    }
+
+    #[actix_web::test]
+    async fn rename_moves_the_object() {
+        let storage = MemoryStorage::default();
+        storage.put("incoming/a", b"data", "image/png").await.unwrap();
+        storage.put("a.png", b"old", "image/png").await.unwrap();
+        storage.rename("incoming/a", "a.png").await.unwrap();
+
+        assert_eq!(storage.get("a.png").await.unwrap(), b"data");
+        assert!(matches!(storage.get("incoming/a").await, Err(StorageError::NotFound(_))));
+    }
+
+    #[actix_web::test]
+    async fn list_filters_by_prefix() {
+        let storage = MemoryStorage::default();
+        storage.put("incoming/a", b"1", "image/png").await.unwrap();
+        storage.put("incoming/b", b"2", "image/png").await.unwrap();
+        storage.put("c.png", b"3", "image/png").await.unwrap();
+
+        let mut keys: Vec<String> = storage.list("incoming/").await.unwrap()
+            .into_iter()
+            .map(|object| object.key)
+            .collect();
+        keys.sort();
+        assert_eq!(keys, ["incoming/a", "incoming/b"]);
+        assert_eq!(storage.list("").await.unwrap().len(), 3);
+    }
+}
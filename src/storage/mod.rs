@@ -0,0 +1,122 @@
+mod local;
+mod memory;
+mod s3;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+
+use crate::configuration::{FileUrlSettings, StorageSettings};
+use crate::signing::Signer;
+
+pub use local::LocalStorage;
+pub use memory::MemoryStorage;
+pub use s3::S3Storage;
+
+/// Keys below this prefix are direct uploads from clients, not checked yet.
+pub const INCOMING_PREFIX: &str = "incoming/";
+
+pub fn incoming_key(upload_id: uuid::Uuid) -> String {
+    format!("{}{}", INCOMING_PREFIX, upload_id)
+}
+
+
+/// Where uploaded media lives.
+///
+/// Objects are addressed by a relative `key` (e.g. `3f2c...e1.png`), which is what
+/// gets persisted in `posts.img_url`. Handlers only ever talk to this trait, so the
+/// same code runs against the local disk, an S3-compatible bucket or memory.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, key: &str, data: &[u8], content_type: &str) -> Result<(), StorageError>;
+
+    /// Store the file at `path`, without holding it in memory when the backend can stream it.
+    async fn put_file(&self, key: &str, path: &Path, content_type: &str) -> Result<(), StorageError> {
+        let data = tokio::fs::read(path).await?;
+        self.put(key, &data, content_type).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError>;
+
+    /// Write the object to the file at `path`, without holding it in memory when the backend can stream it.
+    async fn get_file(&self, key: &str, path: &Path) -> Result<(), StorageError> {
+        let data = self.get(key).await?;
+        tokio::fs::write(path, data).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+
+    /// Move an object to another key, replacing whatever was there.
+    async fn rename(&self, from: &str, to: &str) -> Result<(), StorageError>;
+
+    /// Every object whose key starts with `prefix`.
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>, StorageError>;
+
+    /// URL clients can `PUT` the object to themselves until `expires_in` runs out, for
+    /// backends that have one. Others receive direct uploads through `/uploads/{token}`.
+    async fn presign_put(&self, _key: &str, _expires_in: Duration) -> Result<Option<String>, StorageError> {
+        Ok(None)
+    }
+
+    /// Where the object sits on this machine, for backends whose files the app serves itself.
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// URL clients fetch the object from, valid for at least `file_urls.expiry_seconds`.
+    async fn url(&self, key: &str) -> Result<String, StorageError>;
+}
+
+/// An object as returned by `Storage::list`.
+#[derive(Debug, Clone)]
+pub struct StoredObject {
+    pub key: String,
+    pub modified: SystemTime
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    NotFound(String),
+    Io(std::io::Error),
+    Backend(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::NotFound(key) => write!(f, "object {} not found", key),
+            StorageError::Io(e) => write!(f, "storage io error: {}", e),
+            StorageError::Backend(e) => write!(f, "storage backend error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => StorageError::NotFound(e.to_string()),
+            _ => StorageError::Io(e),
+        }
+    }
+}
+
+/// Build the storage backend selected in the configuration.
+pub fn build(settings: &StorageSettings, file_urls: &FileUrlSettings) -> Result<Arc<dyn Storage>, StorageError> {
+    let storage: Arc<dyn Storage> = match settings {
+        StorageSettings::Local { root, base_url } => Arc::new(LocalStorage::new(
+            root.into(),
+            base_url.clone(),
+            Signer::new(file_urls.secret.clone()),
+            file_urls.expiry_seconds
+        )),
+        StorageSettings::S3(s3_settings) => Arc::new(S3Storage::new(s3_settings, file_urls.expiry_seconds)?),
+        StorageSettings::Memory => Arc::new(MemoryStorage::default()),
+    };
+    Ok(storage)
+}
@@ -0,0 +1,175 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use moka::sync::Cache;
+use s3::creds::Credentials;
+use s3::error::S3Error;
+use s3::{Bucket, Region};
+use secrecy::ExposeSecret;
+
+use crate::configuration::S3Settings;
+use crate::signing::url_expiry;
+use super::{Storage, StorageError, StoredObject};
+
+
+/// Stores objects in an S3-compatible bucket (AWS, MinIO, R2, ...), handed out through
+/// presigned URLs valid for `url_expiry_seconds`, so the bucket can stay private.
+pub struct S3Storage {
+    bucket: Box<Bucket>,
+    url_expiry_seconds: u64,
+    /// Presigned URLs by key and expiry. Presigning stamps the current time into the URL,
+    /// this keeps it the same for the whole period, like `LocalStorage` URLs.
+    urls: Cache<(String, u64), String>
+}
+
+impl S3Storage {
+    pub fn new(settings: &S3Settings, url_expiry_seconds: u64) -> Result<Self, StorageError> {
+        let region = match &settings.endpoint {
+            Some(endpoint) => Region::Custom {
+                region: settings.region.clone(),
+                endpoint: endpoint.clone()
+            },
+            None => settings.region.parse()
+                .map_err(|e| StorageError::Backend(format!("invalid region: {:?}", e)))?
+        };
+
+        let credentials = Credentials::new(
+            Some(&settings.access_key),
+            Some(settings.secret_key.expose_secret()),
+            None,
+            None,
+            None
+        ).map_err(|e| StorageError::Backend(e.to_string()))?;
+
+        let mut bucket = Bucket::new(&settings.bucket, region, credentials)
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+        if settings.path_style {
+            bucket = bucket.with_path_style();
+        }
+
+        let url_expiry_seconds = url_expiry_seconds.max(1);
+        Ok(S3Storage {
+            bucket,
+            url_expiry_seconds,
+            urls: Cache::builder()
+                .max_capacity(10_000)
+                // Past its period a URL is not handed out again
+                .time_to_live(Duration::from_secs(url_expiry_seconds))
+                .build()
+        })
+    }
+}
+
+fn backend_error(key: &str, e: S3Error) -> StorageError {
+    match e {
+        S3Error::HttpFailWithBody(404, _) => StorageError::NotFound(key.to_string()),
+        e => StorageError::Backend(e.to_string())
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, data: &[u8], content_type: &str) -> Result<(), StorageError> {
+        self.bucket.put_object_with_content_type(key, data, content_type).await
+            .map_err(|e| backend_error(key, e))?;
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, path: &Path, content_type: &str) -> Result<(), StorageError> {
+        // Sent as a multipart upload in chunks, the file is never read whole
+        let mut file = tokio::fs::File::open(path).await?;
+        self.bucket.put_object_stream_with_content_type(&mut file, key, content_type).await
+            .map_err(|e| backend_error(key, e))?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        let res = self.bucket.get_object(key).await
+            .map_err(|e| backend_error(key, e))?;
+        Ok(res.to_vec())
+    }
+
+    async fn get_file(&self, key: &str, path: &Path) -> Result<(), StorageError> {
+        let mut file = tokio::fs::File::create(path).await?;
+        self.bucket.get_object_to_writer(key, &mut file).await
+            .map_err(|e| backend_error(key, e))?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.bucket.delete_object(key).await
+            .map_err(|e| backend_error(key, e))?;
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        // S3 has no move, copy then delete. The copy keeps the content type
+        self.bucket.copy_object_internal(from, to).await
+            .map_err(|e| backend_error(from, e))?;
+        self.delete(from).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>, StorageError> {
+        let pages = self.bucket.list(prefix.to_string(), None).await
+            .map_err(|e| backend_error(prefix, e))?;
+        pages.into_iter()
+            .flat_map(|page| page.contents)
+            .map(|object| {
+                let modified = chrono::DateTime::parse_from_rfc3339(&object.last_modified)
+                    .map_err(|e| StorageError::Backend(format!("invalid LastModified of {}: {}", object.key, e)))?;
+                Ok(StoredObject {
+                    key: object.key,
+                    modified: SystemTime::from(modified)
+                })
+            })
+            .collect()
+    }
+
+    async fn presign_put(&self, key: &str, expires_in: Duration) -> Result<Option<String>, StorageError> {
+        let url = self.bucket.presign_put(key, expires_in.as_secs() as u32, None, None).await
+            .map_err(|e| backend_error(key, e))?;
+        Ok(Some(url))
+    }
+
+    async fn url(&self, key: &str) -> Result<String, StorageError> {
+        let expires = url_expiry(self.url_expiry_seconds);
+        let cache_key = (key.to_string(), expires);
+        if let Some(url) = self.urls.get(&cache_key) {
+            return Ok(url)
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let url = self.bucket.presign_get(key, (expires - now) as u32, None).await
+            .map_err(|e| backend_error(key, e))?;
+        self.urls.insert(cache_key, url.clone());
+        Ok(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::Secret;
+    use super::*;
+
+    fn storage() -> S3Storage {
+        S3Storage::new(&S3Settings {
+            bucket: "media".into(),
+            region: "us-east-1".into(),
+            endpoint: Some("http://localhost:9000".into()),
+            access_key: "access".into(),
+            secret_key: Secret::new("secret".into()),
+            path_style: true
+        }, 3600).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn urls_are_presigned_once_per_period() {
+        let storage = storage();
+        let url = storage.url("a.png").await.unwrap();
+        assert!(url.starts_with("http://localhost:9000/media/a.png?"), "{}", url);
+        assert!(url.contains("X-Amz-Signature="), "{} is not presigned", url);
+        assert_eq!(storage.url("a.png").await.unwrap(), url);
+        assert_ne!(storage.url("b.png").await.unwrap(), url);
+    }
+}
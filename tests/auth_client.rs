@@ -0,0 +1,165 @@
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{web, App, HttpResponse, HttpServer};
+use poster::auth::{AuthClient, Credentials};
+use poster::configuration::{AuthClientSettings, AuthMode, CredentialSource, FailurePolicy};
+
+
+/// Stands in for the auth service: a token is valid when it is `good-<username>`.
+#[derive(Default)]
+struct MockAuth {
+    hits: AtomicUsize,
+    down: AtomicBool
+}
+
+impl MockAuth {
+    fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+
+    fn go_down(&self) {
+        self.down.store(true, Ordering::SeqCst);
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Verify {
+    username: String,
+    access_token: String
+}
+
+async fn verify(mock: web::Data<MockAuth>, body: web::Json<Verify>) -> HttpResponse {
+    mock.hits.fetch_add(1, Ordering::SeqCst);
+    if mock.down.load(Ordering::SeqCst) {
+        HttpResponse::InternalServerError().finish()
+    } else if body.access_token == format!("good-{}", body.username) {
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::Unauthorized().finish()
+    }
+}
+
+/// Start the mock on a random port, returning it along with its `/auth` URL.
+fn spawn_mock_auth() -> (Arc<MockAuth>, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind a random port");
+    let port = listener.local_addr().unwrap().port();
+    let mock = web::Data::new(MockAuth::default());
+    let state = mock.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(state.clone())
+            .route("/auth", web::post().to(verify))
+    })
+        .listen(listener)
+        .expect("Failed to listen")
+        .workers(1)
+        .run();
+    actix_web::rt::spawn(server);
+    (mock.into_inner(), format!("http://127.0.0.1:{}/auth", port))
+}
+
+fn client(base_url: String, failure_policy: FailurePolicy, positive_ttl_seconds: u64, negative_ttl_seconds: u64) -> AuthClient {
+    AuthClient::new(&AuthClientSettings {
+        mode: AuthMode::Remote,
+        base_url,
+        timeout_milliseconds: 2000,
+        cache_capacity: 100,
+        positive_ttl_seconds,
+        negative_ttl_seconds,
+        failure_policy,
+        open_grace_seconds: 3600,
+        jwt: None,
+        credential_sources: vec![CredentialSource::Bearer],
+        realm: "poster".into()
+    }).expect("Failed to build the auth client")
+}
+
+fn credentials(username: &str, access_token: &str) -> Credentials {
+    Credentials {
+        username: Some(username.into()),
+        access_token: access_token.into()
+    }
+}
+
+fn status(error: actix_web::Error) -> u16 {
+    error.as_response_error().status_code().as_u16()
+}
+
+#[actix_web::test]
+async fn verifications_are_cached() {
+    let (mock, url) = spawn_mock_auth();
+    let client = client(url, FailurePolicy::Closed, 60, 60);
+
+    let alice = credentials("alice", "good-alice");
+    assert_eq!(client.authorize(&alice).await.unwrap(), Some("alice".into()));
+    assert_eq!(mock.hits(), 1);
+    assert_eq!(client.authorize(&alice).await.unwrap(), Some("alice".into()));
+    assert_eq!(mock.hits(), 1);
+
+    // Another token for the same user is a miss
+    assert_eq!(client.authorize(&credentials("alice", "good-bob")).await.unwrap(), None);
+    assert_eq!(mock.hits(), 2);
+}
+
+#[actix_web::test]
+async fn denials_are_cached() {
+    let (mock, url) = spawn_mock_auth();
+    let client = client(url, FailurePolicy::Closed, 60, 60);
+
+    let mallory = credentials("mallory", "stolen");
+    assert_eq!(client.authorize(&mallory).await.unwrap(), None);
+    assert_eq!(client.authorize(&mallory).await.unwrap(), None);
+    assert_eq!(mock.hits(), 1);
+}
+
+#[actix_web::test]
+async fn cached_verifications_expire() {
+    let (mock, url) = spawn_mock_auth();
+    let client = client(url, FailurePolicy::Closed, 1, 1);
+
+    let alice = credentials("alice", "good-alice");
+    let mallory = credentials("mallory", "stolen");
+    assert!(client.authorize(&alice).await.unwrap().is_some());
+    assert!(client.authorize(&mallory).await.unwrap().is_none());
+    assert_eq!(mock.hits(), 2);
+
+    actix_web::rt::time::sleep(Duration::from_millis(1500)).await;
+    assert!(client.authorize(&alice).await.unwrap().is_some());
+    assert!(client.authorize(&mallory).await.unwrap().is_none());
+    assert_eq!(mock.hits(), 4);
+}
+
+#[actix_web::test]
+async fn closed_policy_rejects_when_the_service_is_down() {
+    let (mock, url) = spawn_mock_auth();
+    let client = client(url, FailurePolicy::Closed, 0, 0);
+
+    let alice = credentials("alice", "good-alice");
+    assert!(client.authorize(&alice).await.unwrap().is_some());
+
+    mock.go_down();
+    assert_eq!(status(client.authorize(&alice).await.unwrap_err()), 503);
+}
+
+#[actix_web::test]
+async fn open_policy_only_lets_earlier_verifications_in() {
+    let (mock, url) = spawn_mock_auth();
+    // Nothing cached for long, every request reaches the service
+    let client = client(url, FailurePolicy::Open, 0, 0);
+
+    let alice = credentials("alice", "good-alice");
+    assert!(client.authorize(&alice).await.unwrap().is_some());
+    let mallory = credentials("mallory", "stolen");
+    assert!(client.authorize(&mallory).await.unwrap().is_none());
+
+    mock.go_down();
+    assert_eq!(client.authorize(&alice).await.unwrap(), Some("alice".into()));
+    assert_eq!(mock.hits(), 3);
+    // Neither denied nor unknown credentials get through
+    assert_eq!(status(client.authorize(&mallory).await.unwrap_err()), 503);
+    assert_eq!(status(client.authorize(&credentials("bob", "good-bob")).await.unwrap_err()), 503);
+    assert_eq!(status(client.authorize(&credentials("alice", "other")).await.unwrap_err()), 503);
+}
@@ -0,0 +1,220 @@
+//! The `/uploads/{token}` flow backends without presigned URLs fall back to. Needs the
+//! database from the configuration, migrated.
+use std::io::Cursor;
+use std::net::TcpListener;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use image::{ImageFormat, Rgb, RgbImage};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use poster::auth::AuthClient;
+use poster::configuration::{
+    get_configuration, AuthMode, DirectUploadSettings, JwtAlgorithm, JwtSettings, StorageSettings
+};
+use poster::signing::Signer;
+use poster::startup::run;
+use poster::storage;
+use secrecy::Secret;
+use serde_json::{json, Value};
+use sqlx::postgres::PgPoolOptions;
+use uuid::Uuid;
+
+
+const JWT_SECRET: &str = "direct-uploads-test-secret";
+
+struct TestApp {
+    address: String,
+    direct_uploads: DirectUploadSettings,
+    client: reqwest::Client,
+    // Removed along with the app
+    _directory: tempfile::TempDir
+}
+
+impl TestApp {
+    /// An access token for `username`, checked locally in jwt mode.
+    fn token(username: &str) -> String {
+        let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 600;
+        encode(
+            &Header::default(),
+            &json!({ "sub": username, "exp": exp }),
+            &EncodingKey::from_secret(JWT_SECRET.as_bytes())
+        ).unwrap()
+    }
+
+    async fn announce(&self, username: &str, data: &[u8]) -> Value {
+        let response = self.client.post(format!("{}/uploads", self.address))
+            .bearer_auth(Self::token(username))
+            .json(&json!({ "content_type": "image/png", "size": data.len() }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        response.json().await.unwrap()
+    }
+
+    async fn put(&self, url: &str, content_type: &str, data: Vec<u8>) -> u16 {
+        self.client.put(format!("{}{}", self.address, url))
+            .header("Content-Type", content_type)
+            .body(data)
+            .send()
+            .await
+            .unwrap()
+            .status()
+            .as_u16()
+    }
+
+    async fn finish(&self, username: &str, upload_id: &str) -> reqwest::Response {
+        self.client.post(format!("{}/uploads/posts", self.address))
+            .bearer_auth(Self::token(username))
+            .json(&json!({ "media": [{ "upload_id": upload_id }] }))
+            .send()
+            .await
+            .unwrap()
+    }
+}
+
+async fn spawn_app() -> TestApp {
+    let directory = tempfile::tempdir().unwrap();
+    let mut configuration = get_configuration().expect("Failed to read configuration");
+    configuration.auth_client.mode = AuthMode::Jwt;
+    configuration.auth_client.jwt = Some(JwtSettings {
+        algorithm: JwtAlgorithm::HS256,
+        secret: Some(Secret::new(JWT_SECRET.into())),
+        jwks_path: None,
+        audience: None,
+        username_claim: "sub".into(),
+        leeway_seconds: 0
+    });
+    // In memory, which can't presign uploads
+    configuration.storage = StorageSettings::Memory;
+    configuration.resumable.directory = directory.path().join("uploads").to_string_lossy().into_owned();
+    configuration.images.cache_directory = directory.path().join("cache").to_string_lossy().into_owned();
+
+    let pool = PgPoolOptions::new()
+        .connect_timeout(Duration::from_secs(2))
+        .connect_lazy_with(configuration.database.with_db());
+    let auth_client = AuthClient::new(&configuration.auth_client).unwrap();
+    let storage = storage::build(&configuration.storage, &configuration.file_urls).unwrap();
+    let direct_uploads = DirectUploadSettings {
+        secret: configuration.direct_uploads.secret.clone(),
+        expiry_seconds: configuration.direct_uploads.expiry_seconds
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind a random port");
+    let address = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+    let server = run(
+        listener,
+        pool,
+        auth_client,
+        storage,
+        configuration.media,
+        configuration.resumable,
+        configuration.direct_uploads,
+        configuration.file_urls,
+        configuration.images
+    ).expect("Failed to start the app");
+    actix_web::rt::spawn(server);
+
+    TestApp {
+        address,
+        direct_uploads,
+        client: reqwest::Client::new(),
+        _directory: directory
+    }
+}
+
+fn png() -> Vec<u8> {
+    let mut data = Vec::new();
+    RgbImage::from_pixel(16, 16, Rgb([200, 30, 30]))
+        .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+        .unwrap();
+    data
+}
+
+#[actix_web::test]
+async fn signed_upload_urls_take_the_file_for_the_post() {
+    let app = spawn_app().await;
+    let data = png();
+
+    let upload = app.announce("alice", &data).await;
+    let upload_id = upload["upload_id"].as_str().unwrap();
+    let url = upload["url"].as_str().unwrap();
+    assert!(url.starts_with("/uploads/"), "{} is not signed by the app", url);
+    assert_eq!(upload["method"], "PUT");
+
+    // The URL is the credential, no access token goes with it
+    assert_eq!(app.put(url, "image/png", data).await, 200);
+
+    // Only its owner can post it
+    assert_eq!(app.finish("bob", upload_id).await.status().as_u16(), 404);
+    let response = app.finish("alice", upload_id).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let created: Value = response.json().await.unwrap();
+    let post_id = created["id"].as_str().unwrap();
+
+    let post: Value = app.client.get(format!("{}/post/{}", app.address, post_id))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(post["username"], "alice");
+    assert_eq!(post["media"].as_array().unwrap().len(), 1);
+
+    // Posted once
+    assert_eq!(app.finish("alice", upload_id).await.status().as_u16(), 404);
+}
+
+#[actix_web::test]
+async fn concurrent_finishes_post_an_upload_once() {
+    let app = spawn_app().await;
+    let data = png();
+    let upload = app.announce("alice", &data).await;
+    let upload_id = upload["upload_id"].as_str().unwrap();
+    assert_eq!(app.put(upload["url"].as_str().unwrap(), "image/png", data).await, 200);
+
+    let (first, second) = futures_util::join!(app.finish("alice", upload_id), app.finish("alice", upload_id));
+    let mut statuses = [first.status().as_u16(), second.status().as_u16()];
+    statuses.sort();
+    // Turned away while the other holds the upload, or not found once it is posted
+    assert_eq!(statuses[0], 200, "{:?}", statuses);
+    assert!([404, 409].contains(&statuses[1]), "{:?}", statuses);
+}
+
+#[actix_web::test]
+async fn uploads_are_checked_against_their_announcement() {
+    let app = spawn_app().await;
+    let data = png();
+    let upload = app.announce("alice", &data).await;
+    let url = upload["url"].as_str().unwrap();
+
+    assert_eq!(app.put(url, "image/jpeg", data.clone()).await, 415);
+    let mut longer = data.clone();
+    longer.push(0);
+    assert_eq!(app.put(url, "image/png", longer).await, 413);
+}
+
+#[actix_web::test]
+async fn forged_or_expired_upload_urls_are_refused() {
+    let app = spawn_app().await;
+    let data = png();
+    let upload = app.announce("alice", &data).await;
+    let url = upload["url"].as_str().unwrap();
+    let upload_id = upload["upload_id"].as_str().unwrap();
+
+    // Another upload under the same signature
+    let forged = url.replace(upload_id, &Uuid::new_v4().to_string());
+    assert_eq!(app.put(&forged, "image/png", data.clone()).await, 403);
+
+    let signer = Signer::new(app.direct_uploads.secret.clone());
+    let expired = signer.sign(upload_id, SystemTime::now() - Duration::from_secs(1));
+    assert_eq!(app.put(&format!("/uploads/{}", expired), "image/png", data.clone()).await, 403);
+
+    let other_key = Signer::new(Secret::new("another-secret".into()))
+        .sign(upload_id, SystemTime::now() + Duration::from_secs(60));
+    assert_eq!(app.put(&format!("/uploads/{}", other_key), "image/png", data.clone()).await, 403);
+
+    // Signed, but never announced
+    let unknown = signer.sign(&Uuid::new_v4().to_string(), SystemTime::now() + Duration::from_secs(60));
+    assert_eq!(app.put(&format!("/uploads/{}", unknown), "image/png", data).await, 404);
+}
@@ -0,0 +1,321 @@
+//! Reading and editing posts and their comments over HTTP. Needs the database from the configuration, migrated.
+use std::io::Cursor;
+use std::net::TcpListener;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::DateTime;
+use image::{ImageFormat, Rgb, RgbImage};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use poster::auth::AuthClient;
+use poster::configuration::{get_configuration, AuthMode, JwtAlgorithm, JwtSettings, StorageSettings};
+use poster::startup::run;
+use poster::storage;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::Response;
+use secrecy::Secret;
+use serde_json::{json, Value};
+use sqlx::postgres::PgPoolOptions;
+use uuid::Uuid;
+
+
+const JWT_SECRET: &str = "posts-test-secret";
+
+struct TestApp {
+    address: String,
+    client: reqwest::Client,
+    // Removed along with the app
+    _directory: tempfile::TempDir
+}
+
+impl TestApp {
+    /// An access token for `username`, checked locally in jwt mode.
+    fn token(username: &str) -> String {
+        let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 600;
+        encode(
+            &Header::default(),
+            &json!({ "sub": username, "exp": exp }),
+            &EncodingKey::from_secret(JWT_SECRET.as_bytes())
+        ).unwrap()
+    }
+
+    /// A post by `username` with one image, through the direct upload flow.
+    async fn create_post(&self, username: &str) -> String {
+        let data = png();
+        let upload: Value = self.client.post(format!("{}/uploads", self.address))
+            .bearer_auth(Self::token(username))
+            .json(&json!({ "content_type": "image/png", "size": data.len() }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let status = self.client.put(format!("{}{}", self.address, upload["url"].as_str().unwrap()))
+            .header("Content-Type", "image/png")
+            .body(data)
+            .send()
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(status.as_u16(), 200);
+
+        let created: Value = self.client.post(format!("{}/uploads/posts", self.address))
+            .bearer_auth(Self::token(username))
+            .json(&json!({ "media": [{ "upload_id": upload["upload_id"] }] }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        created["id"].as_str().unwrap().to_string()
+    }
+
+    async fn get_post(&self, id: &str, headers: &[(&str, &str)]) -> Response {
+        let mut request = self.client.get(format!("{}/post/{}", self.address, id));
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.send().await.unwrap()
+    }
+
+    async fn comment(&self, username: &str, post_id: &str, body: &str) -> Response {
+        self.client.post(format!("{}/posts/{}/comments", self.address, post_id))
+            .bearer_auth(Self::token(username))
+            .json(&json!({ "body": body }))
+            .send()
+            .await
+            .unwrap()
+    }
+
+    async fn comments(&self, post_id: &str, query: &str) -> Response {
+        self.client.get(format!("{}/posts/{}/comments?{}", self.address, post_id, query))
+            .send()
+            .await
+            .unwrap()
+    }
+
+    async fn edit_comment(&self, username: &str, post_id: &str, comment_id: &str, body: &str) -> Response {
+        self.client.patch(format!("{}/posts/{}/comments/{}", self.address, post_id, comment_id))
+            .bearer_auth(Self::token(username))
+            .json(&json!({ "body": body }))
+            .send()
+            .await
+            .unwrap()
+    }
+
+    async fn delete_comment(&self, username: &str, post_id: &str, comment_id: &str) -> u16 {
+        self.client.delete(format!("{}/posts/{}/comments/{}", self.address, post_id, comment_id))
+            .bearer_auth(Self::token(username))
+            .send()
+            .await
+            .unwrap()
+            .status()
+            .as_u16()
+    }
+}
+
+/// `file_url_seconds` is how long signed file URLs stay the same.
+async fn spawn_app(file_url_seconds: u64) -> TestApp {
+    let directory = tempfile::tempdir().unwrap();
+    let mut configuration = get_configuration().expect("Failed to read configuration");
+    configuration.auth_client.mode = AuthMode::Jwt;
+    configuration.auth_client.jwt = Some(JwtSettings {
+        algorithm: JwtAlgorithm::HS256,
+        secret: Some(Secret::new(JWT_SECRET.into())),
+        jwks_path: None,
+        audience: None,
+        username_claim: "sub".into(),
+        leeway_seconds: 0
+    });
+    configuration.storage = StorageSettings::Memory;
+    configuration.file_urls.expiry_seconds = file_url_seconds;
+    configuration.resumable.directory = directory.path().join("uploads").to_string_lossy().into_owned();
+    configuration.images.cache_directory = directory.path().join("cache").to_string_lossy().into_owned();
+
+    let pool = PgPoolOptions::new()
+        .connect_timeout(Duration::from_secs(2))
+        .connect_lazy_with(configuration.database.with_db());
+    let auth_client = AuthClient::new(&configuration.auth_client).unwrap();
+    let storage = storage::build(&configuration.storage, &configuration.file_urls).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind a random port");
+    let address = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+    let server = run(
+        listener,
+        pool,
+        auth_client,
+        storage,
+        configuration.media,
+        configuration.resumable,
+        configuration.direct_uploads,
+        configuration.file_urls,
+        configuration.images
+    ).expect("Failed to start the app");
+    actix_web::rt::spawn(server);
+
+    TestApp {
+        address,
+        client: reqwest::Client::new(),
+        _directory: directory
+    }
+}
+
+fn png() -> Vec<u8> {
+    let mut data = Vec::new();
+    RgbImage::from_pixel(16, 16, Rgb([30, 200, 30]))
+        .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+        .unwrap();
+    data
+}
+
+fn header(response: &Response, name: reqwest::header::HeaderName) -> String {
+    response.headers().get(name).unwrap().to_str().unwrap().to_string()
+}
+
+fn timestamp(http_date: &str) -> i64 {
+    DateTime::parse_from_rfc2822(http_date).unwrap().timestamp()
+}
+
+#[actix_web::test]
+async fn caption_edits_change_the_post_validators() {
+    // Long enough for the signed URLs to stay put during the test
+    let app = spawn_app(86400).await;
+    let id = app.create_post("alice").await;
+
+    let first = app.get_post(&id, &[]).await;
+    assert_eq!(first.status().as_u16(), 200);
+    let etag = header(&first, ETAG);
+    let last_modified = header(&first, LAST_MODIFIED);
+
+    let weak = format!("W/{}", etag);
+    assert_eq!(app.get_post(&id, &[(IF_NONE_MATCH.as_str(), &etag)]).await.status().as_u16(), 304);
+    assert_eq!(app.get_post(&id, &[(IF_NONE_MATCH.as_str(), &weak)]).await.status().as_u16(), 304);
+    assert_eq!(app.get_post(&id, &[(IF_MODIFIED_SINCE.as_str(), &last_modified)]).await.status().as_u16(), 304);
+
+    // Last-Modified has whole seconds
+    actix_web::rt::time::sleep(Duration::from_millis(1100)).await;
+    let status = app.client.patch(format!("{}/posts", app.address))
+        .bearer_auth(TestApp::token("alice"))
+        .json(&json!({ "id": id, "caption": "edited" }))
+        .send()
+        .await
+        .unwrap()
+        .status();
+    assert_eq!(status.as_u16(), 200);
+
+    let edited = app.get_post(&id, &[(IF_NONE_MATCH.as_str(), &etag)]).await;
+    assert_eq!(edited.status().as_u16(), 200);
+    assert_ne!(header(&edited, ETAG), etag);
+    assert!(timestamp(&header(&edited, LAST_MODIFIED)) > timestamp(&last_modified));
+    let post: Value = edited.json().await.unwrap();
+    assert_eq!(post["caption"], "edited");
+
+    assert_eq!(app.get_post(&id, &[(IF_MODIFIED_SINCE.as_str(), &last_modified)]).await.status().as_u16(), 200);
+}
+
+#[actix_web::test]
+async fn new_signed_urls_change_the_post_validators() {
+    let app = spawn_app(1).await;
+    let id = app.create_post("alice").await;
+
+    let first = app.get_post(&id, &[]).await;
+    let etag = header(&first, ETAG);
+    let last_modified = header(&first, LAST_MODIFIED);
+
+    // The next period hands out other URLs for the same files
+    actix_web::rt::time::sleep(Duration::from_millis(1100)).await;
+    let later = app.get_post(&id, &[
+        (IF_NONE_MATCH.as_str(), &etag),
+        (IF_MODIFIED_SINCE.as_str(), &last_modified)
+    ]).await;
+    assert_eq!(later.status().as_u16(), 200);
+    assert_ne!(header(&later, ETAG), etag);
+    assert!(timestamp(&header(&later, LAST_MODIFIED)) > timestamp(&last_modified));
+
+    // Only If-Modified-Since, which is all some caches send
+    let since = app.get_post(&id, &[(IF_MODIFIED_SINCE.as_str(), &last_modified)]).await;
+    assert_eq!(since.status().as_u16(), 200);
+}
+
+#[actix_web::test]
+async fn comments_are_listed_oldest_first_in_pages() {
+    let app = spawn_app(86400).await;
+    let post_id = app.create_post("alice").await;
+
+    for (username, body) in [("bob", "one"), ("alice", "two"), ("carol", "three"), ("bob", "four"), ("bob", "five")] {
+        let response = app.comment(username, &post_id, body).await;
+        assert_eq!(response.status().as_u16(), 200);
+        let comment: Value = response.json().await.unwrap();
+        assert_eq!(comment["username"], username);
+        assert_eq!(comment["body"], body);
+    }
+
+    let mut bodies = Vec::new();
+    let mut query = "limit=2".to_string();
+    loop {
+        let response = app.comments(&post_id, &query).await;
+        assert_eq!(response.status().as_u16(), 200);
+        let page: Value = response.json().await.unwrap();
+        let comments = page["comments"].as_array().unwrap();
+        assert!(comments.len() <= 2);
+        bodies.extend(comments.iter().map(|c| c["body"].as_str().unwrap().to_string()));
+        match page["next_cursor"].as_str() {
+            Some(cursor) => query = format!("limit=2&cursor={}", cursor),
+            None => break
+        }
+    }
+    assert_eq!(bodies, ["one", "two", "three", "four", "five"]);
+
+    // The default page holds them all
+    let page: Value = app.comments(&post_id, "").await.json().await.unwrap();
+    assert_eq!(page["comments"].as_array().unwrap().len(), 5);
+    assert_eq!(page["next_cursor"], Value::Null);
+
+    assert_eq!(app.comments(&post_id, "limit=0").await.status().as_u16(), 422);
+    assert_eq!(app.comments(&post_id, "cursor=nope").await.status().as_u16(), 400);
+}
+
+#[actix_web::test]
+async fn comments_on_missing_posts_are_not_found() {
+    let app = spawn_app(86400).await;
+    let missing = Uuid::new_v4().to_string();
+
+    assert_eq!(app.comments(&missing, "").await.status().as_u16(), 404);
+    assert_eq!(app.comment("bob", &missing, "hello").await.status().as_u16(), 404);
+
+    // A post without comments is there all the same
+    let post_id = app.create_post("alice").await;
+    let page: Value = app.comments(&post_id, "").await.json().await.unwrap();
+    assert_eq!(page["comments"], json!([]));
+}
+
+#[actix_web::test]
+async fn only_authors_edit_comments_and_post_owners_remove_them_too() {
+    let app = spawn_app(86400).await;
+    let post_id = app.create_post("alice").await;
+    let comment: Value = app.comment("bob", &post_id, "first").await.json().await.unwrap();
+    let comment_id = comment["id"].as_str().unwrap();
+    assert_eq!(comment["updated_at"], Value::Null);
+
+    assert_eq!(app.edit_comment("alice", &post_id, comment_id, "mine now").await.status().as_u16(), 403);
+    assert_eq!(app.edit_comment("bob", &post_id, comment_id, "").await.status().as_u16(), 422);
+    assert_eq!(app.edit_comment("bob", &post_id, &Uuid::new_v4().to_string(), "edited").await.status().as_u16(), 404);
+
+    let response = app.edit_comment("bob", &post_id, comment_id, "edited").await;
+    assert_eq!(response.status().as_u16(), 200);
+    let edited: Value = response.json().await.unwrap();
+    assert_eq!(edited["body"], "edited");
+    assert_ne!(edited["updated_at"], Value::Null);
+
+    // Neither the author nor the owner of the post
+    assert_eq!(app.delete_comment("carol", &post_id, comment_id).await, 403);
+    assert_eq!(app.delete_comment("alice", &post_id, comment_id).await, 200);
+    assert_eq!(app.delete_comment("bob", &post_id, comment_id).await, 404);
+
+    let own: Value = app.comment("bob", &post_id, "second").await.json().await.unwrap();
+    assert_eq!(app.delete_comment("bob", &post_id, own["id"].as_str().unwrap()).await, 200);
+
+    let page: Value = app.comments(&post_id, "").await.json().await.unwrap();
+    assert_eq!(page["comments"], json!([]));
+}